@@ -1,74 +1,445 @@
-use crate::{Node, Nodes, BROADCAST_INTERVAL, DNS_CHECK_INTERVAL};
-use if_addrs::get_if_addrs;
+use crate::health::{run_health_checks, HealthCheck};
+pub use crate::net::get_ip;
+use crate::net::{get_ipv4s, local_ips};
+#[cfg(feature = "serde")]
+use crate::persist::{run_persister, Persistence};
+use crate::{
+    run_reaper, stats, AddOutcome, DiscoveryError, Node, Nodes, TagLimitPolicy, Tasks,
+    DNS_CHECK_INTERVAL, MAX_SILENT_INTERVALS,
+};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use rustdns::types::*;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::future::Future;
 use std::io;
-use std::net::IpAddr;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::net::UdpSocket;
+use std::time::SystemTime;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
 use tokio::sync::{oneshot, watch};
-use tokio::time::{sleep, timeout, Duration};
-use tracing::{debug, error, info, warn};
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout_at, Duration, Instant};
+use tracing::field;
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
 
+/// CNAMEs followed from one name before giving up on it.
+const MAX_CNAME_HOPS: usize = 8;
+
+/// Called after every completed scan with the node table and that round's
+/// summary.
+pub type ScanCallback = Arc<dyn Fn(&Nodes, ScanSummary) + Send + Sync>;
+
+/// Optional knobs for [`discover`]; `DnsConfig::default()` keeps the stock
+/// behaviour.
+#[derive(Clone)]
+pub struct DnsConfig {
+    pub on_scan_complete: Option<ScanCallback>,
+    /// Also accept A records from the additional and authority sections when
+    /// they belong to the queried name or a CNAME target, saving a follow-up
+    /// query when the resolver supplies glue.
+    pub use_additional_records: bool,
+    /// Peers inserted before the first scan so the table isn't empty while it
+    /// runs.
+    pub seed_nodes: Vec<(Ipv4Addr, Option<String>, Option<u32>)>,
+    /// Seeds every random choice the session makes (query IDs, and the
+    /// table's [`pick_random`](Nodes::pick_random) and
+    /// [`pick_weighted`](Nodes::pick_weighted)), so a run can be reproduced
+    /// in tests. `None` seeds from entropy.
+    pub rng_seed: Option<u64>,
+    /// EDNS UDP payload size we advertise, and the size of the buffer we read
+    /// responses into. Lower it to work around path-MTU trouble to the
+    /// resolver.
+    pub edns_payload_size: u16,
+    /// Consecutive scans resolving nothing at all before the session is
+    /// flagged degraded. Such a scan almost always means the resolver is down
+    /// rather than the cluster being empty, so while degraded nodes are not
+    /// reaped. `0` never degrades.
+    pub degraded_after: u32,
+    /// Most nodes expected per tag; see [`Nodes::with_tag_limit`].
+    pub tag_limits: HashMap<String, usize>,
+    pub on_tag_limit: TagLimitPolicy,
+    /// A second, independent resolver that must give the same answer before
+    /// a node is trusted, guarding against one poisoned or stale resolver.
+    /// Disagreements are logged and counted in [`ScanSummary::mismatches`].
+    pub confirm_with: Option<SocketAddr>,
+    /// Highest `seq` looked up per tag.
+    pub max_seq: u32,
+    /// Stop a tag at the first `seq` with no record. Turn off to probe all
+    /// the way to `max_seq`, so one missing node doesn't hide those after
+    /// it, at the cost of `max_seq` queries per tag every scan.
+    pub stop_on_gap: bool,
+    /// Also look up AAAA records and attach them to each node (see
+    /// [`Node::ipv6_addrs`]). The table is keyed by IPv4 address, so names
+    /// with only AAAA records are kept apart, in [`Nodes::ipv6_only`], and
+    /// counted in [`ScanSummary::ipv6_only`].
+    pub query_aaaa: bool,
+    /// Queries in flight at once. Above 1, each tag is probed over its whole
+    /// `1..=max_seq` range concurrently, collecting every hit, instead of
+    /// one name at a time stopping at the first gap.
+    pub concurrency: usize,
+    /// How long to wait for an answer to each query.
+    pub query_timeout: Duration,
+    /// Times an unanswered query is resent before it counts as an error.
+    pub retries: u32,
+    /// Wait before the first retry, doubling for each after that.
+    pub base_backoff: Duration,
+    /// Time between scans after the first when the answers carry no TTL, as
+    /// with a hosts file, and the longest wait otherwise.
+    pub check_interval: Duration,
+    /// Shortest wait between scans. The next scan is due when the shortest
+    /// TTL seen in the last one runs out, kept between this and
+    /// `check_interval` so a TTL of 0 doesn't hammer the resolver.
+    pub min_check_interval: Duration,
+    /// How often nodes that have dropped out of DNS are looked for. `None`
+    /// checks once per `check_interval`.
+    pub reap_interval: Option<Duration>,
+    /// Also look up TXT records at each name and attach their `key=value`
+    /// strings as [`Node::metadata`]. Strings without an `=` are skipped.
+    pub query_txt: bool,
+    /// Probe every node found and mark those that don't answer unhealthy.
+    pub health_check: Option<HealthCheck>,
+    /// Restore the nodes saved here at startup and keep saving them, so a
+    /// restart begins with the last-known peers rather than none.
+    #[cfg(feature = "serde")]
+    pub persist: Option<Persistence>,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        DnsConfig {
+            on_scan_complete: None,
+            use_additional_records: false,
+            seed_nodes: Vec::new(),
+            rng_seed: None,
+            edns_payload_size: 4096,
+            degraded_after: 3,
+            tag_limits: HashMap::new(),
+            on_tag_limit: TagLimitPolicy::Warn,
+            confirm_with: None,
+            max_seq: 99,
+            stop_on_gap: true,
+            query_aaaa: false,
+            concurrency: 1,
+            query_timeout: Duration::from_secs(5),
+            retries: 2,
+            base_backoff: Duration::from_millis(200),
+            check_interval: DNS_CHECK_INTERVAL,
+            min_check_interval: Duration::from_secs(30),
+            reap_interval: None,
+            query_txt: false,
+            health_check: None,
+            #[cfg(feature = "serde")]
+            persist: None,
+        }
+    }
+}
+
+impl fmt::Debug for DnsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_struct("DnsConfig");
+        s.field("on_scan_complete", &self.on_scan_complete.is_some())
+            .field("use_additional_records", &self.use_additional_records)
+            .field("seed_nodes", &self.seed_nodes)
+            .field("rng_seed", &self.rng_seed)
+            .field("edns_payload_size", &self.edns_payload_size)
+            .field("degraded_after", &self.degraded_after)
+            .field("tag_limits", &self.tag_limits)
+            .field("on_tag_limit", &self.on_tag_limit)
+            .field("confirm_with", &self.confirm_with)
+            .field("max_seq", &self.max_seq)
+            .field("stop_on_gap", &self.stop_on_gap)
+            .field("query_aaaa", &self.query_aaaa)
+            .field("concurrency", &self.concurrency)
+            .field("query_timeout", &self.query_timeout)
+            .field("retries", &self.retries)
+            .field("base_backoff", &self.base_backoff)
+            .field("check_interval", &self.check_interval)
+            .field("min_check_interval", &self.min_check_interval)
+            .field("reap_interval", &self.reap_interval)
+            .field("query_txt", &self.query_txt)
+            .field("health_check", &self.health_check);
+        #[cfg(feature = "serde")]
+        s.field("persist", &self.persist);
+        s.finish()
+    }
+}
+
+/// Where [`discover`] looks names up.
+#[derive(Debug, Clone)]
+pub enum DnsSource {
+    /// Query resolvers over UDP. The first is used until it stops answering,
+    /// then the next, and so on round the list.
+    Resolvers(Vec<SocketAddr>),
+    /// Read `name ip` lines from a file instead, e.g. for tests or air-gapped
+    /// hosts. Names may be given with or without the domain, `#` starts a
+    /// comment, and the file is re-read before any scan that finds it
+    /// changed.
+    File(PathBuf),
+}
+
+impl From<SocketAddr> for DnsSource {
+    fn from(addr: SocketAddr) -> Self {
+        DnsSource::Resolvers(vec![addr])
+    }
+}
+
+impl From<Vec<SocketAddr>> for DnsSource {
+    fn from(addrs: Vec<SocketAddr>) -> Self {
+        DnsSource::Resolvers(addrs)
+    }
+}
+
+/// A running DNS discovery session.
+pub struct DnsHandle {
+    pub up: oneshot::Receiver<()>,
+    pub fin: oneshot::Receiver<()>,
+    pub shutdown: watch::Sender<()>,
+    pub nodes: Arc<Nodes>,
+    config: DnsConfig,
+    degraded: Arc<AtomicBool>,
+    tasks: Tasks,
+}
+
+impl DnsHandle {
+    /// False once the re-check task has exited, whether through shutdown or a
+    /// panic.
+    pub fn is_alive(&self) -> bool {
+        self.tasks.is_alive()
+    }
+
+    /// The configuration the session is running with.
+    pub fn config(&self) -> &DnsConfig {
+        &self.config
+    }
+
+    /// True while the last [`DnsConfig::degraded_after`] scans have all come
+    /// back empty. Clears on the first scan that resolves anything.
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::Relaxed)
+    }
+
+    /// Waits for the re-check task to exit, surfacing a panic as
+    /// [`DiscoveryError::TaskFailed`].
+    pub async fn join(&mut self) -> Result<(), DiscoveryError> {
+        self.tasks.join().await
+    }
+}
+
+/// What a single pass of DNS checks saw.
+#[derive(Debug, Clone, Default)]
+pub struct ScanSummary {
+    /// Addresses resolved this round that weren't in the table before it.
+    pub added: Vec<Ipv4Addr>,
+    /// Addresses in the table before the round that it didn't resolve.
+    pub removed: Vec<Ipv4Addr>,
+    /// Known nodes whose tag or seq changed this round.
+    pub updated: usize,
+    pub queries: usize,
+    pub errors: usize,
+    /// Names the [`DnsConfig::confirm_with`] resolver answered differently.
+    pub mismatches: usize,
+    /// Names with AAAA but no A records, each kept in [`Nodes::ipv6_only`];
+    /// see [`DnsConfig::query_aaaa`].
+    pub ipv6_only: usize,
+    /// Names resolved for each configured tag, zero included, so a tag whose
+    /// population drops can be alerted on.
+    pub per_tag: HashMap<String, TagCounts>,
+}
+
+/// How many of a tag's names resolved to peers and how many to us.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TagCounts {
+    pub discovered: usize,
+    pub own: usize,
+}
+
+/// Looks up `{prefix}-{tag}-{seq}.{domain}` for every tag under each of
+/// `domains`, into one table; see [`Node::domain`]. An address found under
+/// several names is one node, taking its tag, seq and domain from the first
+/// name in scan order; later names for it are logged and ignored.
+/// `interfaces` name the interfaces whose addresses are ours; both take
+/// `&str`s or `String`s.
 pub async fn discover(
-    interfaces: Vec<&str>,
-    dns_service: SocketAddr,
+    interfaces: impl IntoIterator<Item = impl Into<String>>,
+    source: impl Into<DnsSource>,
+    domains: impl IntoIterator<Item = impl Into<String>>,
+    prefix: String,
+    tags: Vec<String>,
+    config: DnsConfig,
+) -> Result<DnsHandle, DiscoveryError> {
+    let names = Names::Tags { prefix, tags };
+    let nodes = Arc::new(nodes_for(&config));
+    start(
+        collect(interfaces),
+        source.into(),
+        collect(domains),
+        names,
+        config,
+        nodes,
+    )
+    .await
+}
+
+/// Discovers the targets of the SRV records at `_{service}._{proto}.{domain}`,
+/// e.g. `discover_srv(ifaces, resolver, "api", "tcp", domain, config)`. Each
+/// node is tagged with `service` and carries its record's port and priority;
+/// see [`Nodes::by_priority`]. Confirmation, AAAA and TXT lookups don't apply
+/// to SRV targets.
+pub async fn discover_srv(
+    interfaces: impl IntoIterator<Item = impl Into<String>>,
+    source: impl Into<DnsSource>,
+    service: String,
+    proto: String,
     domain: String,
+    config: DnsConfig,
+) -> Result<DnsHandle, DiscoveryError> {
+    let names = Names::Srv {
+        name: format!(
+            "_{}._{}.{}",
+            service.trim_start_matches('_'),
+            proto.trim_start_matches('_'),
+            domain
+        ),
+        service,
+    };
+    let nodes = Arc::new(nodes_for(&config));
+    start(
+        collect(interfaces),
+        source.into(),
+        vec![domain],
+        names,
+        config,
+        nodes,
+    )
+    .await
+}
+
+pub(crate) fn collect(names: impl IntoIterator<Item = impl Into<String>>) -> Vec<String> {
+    names.into_iter().map(Into::into).collect()
+}
+
+/// What a session looks up each scan.
+pub(crate) enum Names {
+    /// `{prefix}-{tag}-{seq}` under the domain.
+    Tags { prefix: String, tags: Vec<String> },
+    /// The SRV records at `name`, tagging nodes with `service`.
+    Srv { name: String, service: String },
+}
+
+/// The node table a session configured by `config` works with.
+pub(crate) fn nodes_for(config: &DnsConfig) -> Nodes {
+    // a node is refreshed once per scan, so it's gone once it has been
+    // missing from as many scans as a VLAN peer may miss announcements
+    let silence_window = config.check_interval * MAX_SILENT_INTERVALS as u32;
+    let mut nodes = Nodes::new()
+        .with_tag_limit_policy(config.on_tag_limit)
+        .with_silence_window(silence_window);
+    for (tag, max) in &config.tag_limits {
+        nodes = nodes.with_tag_limit(tag.clone(), *max);
+    }
+    if let Some(seed) = config.rng_seed {
+        nodes = nodes.with_rng_seed(seed);
+    }
+    nodes
+}
+
+/// Resolves every name once, with none of the background tasks or channels
+/// of [`discover`], and returns the table in IP order, ourselves included if
+/// a name resolved to us. For one-shot jobs and the CLI.
+pub async fn discover_once(
+    interfaces: impl IntoIterator<Item = impl Into<String>>,
+    source: impl Into<DnsSource>,
+    domains: impl IntoIterator<Item = impl Into<String>>,
     prefix: String,
     tags: Vec<String>,
-) -> Result<
-    (
-        oneshot::Receiver<()>,
-        oneshot::Receiver<()>,
-        watch::Sender<()>,
-        Arc<Nodes>,
-    ),
-    Box<dyn std::error::Error + Send + Sync>,
-> {
+    config: DnsConfig,
+) -> Result<Vec<Node>, DiscoveryError> {
+    let names = Names::Tags { prefix, tags };
+    let nodes = Arc::new(nodes_for(&config));
+    let mut scanner = prepare(
+        collect(interfaces),
+        source.into(),
+        collect(domains),
+        names,
+        config,
+        Arc::clone(&nodes),
+    )
+    .await?;
+    scanner.perform_dns_checks().await;
+    Ok(nodes.ordered())
+}
+
+/// Runs a session over `nodes`, which may be shared with another discovery
+/// source.
+pub(crate) async fn start(
+    interfaces: Vec<String>,
+    source: DnsSource,
+    domains: Vec<String>,
+    names: Names,
+    config: DnsConfig,
+    nodes: Arc<Nodes>,
+) -> Result<DnsHandle, DiscoveryError> {
+    let mut scanner = prepare(
+        interfaces,
+        source,
+        domains,
+        names,
+        config,
+        Arc::clone(&nodes),
+    )
+    .await?;
+    let config = scanner.config.clone();
+    let degraded = Arc::clone(&scanner.degraded);
     let (shutdown_tx, mut shutdown_rx) = watch::channel(());
     let (up_tx, up_rx) = oneshot::channel();
     let (fin_tx, fin_rx) = oneshot::channel();
 
-    let socket = UdpSocket::bind("0.0.0.0:0").await?;
-    socket.connect(dns_service).await?;
-
-    let nodes = Arc::new(Nodes::new());
-    let dns_service = dns_service.clone();
-    let domain = domain.clone();
-    let nodes_clone = Arc::clone(&nodes);
-
-    let mut own_ips = HashSet::new();
-    for interface in interfaces {
-        if let Some(ip) = get_ip(interface) {
-            own_ips.insert(ip);
-            info!("added own public ip {} to ignore list", ip.to_string());
-        }
+    #[cfg(feature = "serde")]
+    if let Some(persist) = &config.persist {
+        nodes.restore(&persist.path);
     }
-    own_ips.insert(Ipv4Addr::new(127, 0, 0, 1));
 
-    perform_dns_checks(
-        &dns_service,
-        &domain,
-        &prefix,
-        &tags,
-        &socket,
-        &nodes_clone,
-        &own_ips,
-    )
-    .await;
+    let mut next_check = scanner.perform_dns_checks().await;
 
     let _ = up_tx.send(());
 
-    tokio::spawn(async move {
+    let reap_interval = config.reap_interval.unwrap_or(config.check_interval);
+    let mut tasks = Tasks::default();
+    tasks.spawn(run_reaper(
+        Arc::clone(&nodes),
+        reap_interval,
+        shutdown_rx.clone(),
+    ));
+    if let Some(check) = config.health_check.clone() {
+        tasks.spawn(run_health_checks(
+            Arc::clone(&nodes),
+            check,
+            shutdown_rx.clone(),
+        ));
+    }
+    #[cfg(feature = "serde")]
+    if let Some(persist) = config.persist.clone() {
+        tasks.spawn(run_persister(
+            Arc::clone(&nodes),
+            persist,
+            shutdown_rx.clone(),
+        ));
+    }
+    tasks.spawn(async move {
         loop {
             tokio::select! {
                 _ = shutdown_rx.changed() => {
                     info!("Shutdown signal received, stopping tasks");
                     break;
                 }
-                _ = sleep(DNS_CHECK_INTERVAL) => {
-                    perform_dns_checks(&dns_service, &domain, &prefix, &tags, &socket, &nodes_clone, &own_ips).await;
+                _ = sleep(next_check) => {
+                    next_check = scanner.perform_dns_checks().await;
                 },
             }
         }
@@ -76,105 +447,1137 @@ pub async fn discover(
         let _ = fin_tx.send(());
     });
 
-    Ok((up_rx, fin_rx, shutdown_tx, Arc::clone(&nodes)))
+    Ok(DnsHandle {
+        up: up_rx,
+        fin: fin_rx,
+        shutdown: shutdown_tx,
+        nodes: Arc::clone(&nodes),
+        config,
+        degraded,
+        tasks,
+    })
 }
 
-async fn perform_dns_checks(
-    dns_service: &SocketAddr,
-    domain: &String,
-    prefix: &String,
-    tags: &[String],
-    socket: &UdpSocket,
-    nodes: &Arc<Nodes>,
-    own_ips: &HashSet<Ipv4Addr>,
-) {
-    for tag in tags {
-        let mut seq = 0;
-        while seq < 100 {
-            seq += 1;
-            let subdomain = format!("{}-{}-{}", prefix, tag, seq);
-            match get_dns(*dns_service, domain.clone(), socket, subdomain.to_string()).await {
-                Ok(Some(ip)) => {
-                    if !nodes.test(&ip) && !own_ips.contains(&ip) {
-                        info!("Discovered new node via DNS: {}", ip);
+/// Checks the settings, sets up the resolver and registers our addresses
+/// and any seeds in `nodes`, ready for the first scan.
+async fn prepare(
+    interfaces: Vec<String>,
+    source: DnsSource,
+    domains: Vec<String>,
+    names: Names,
+    config: DnsConfig,
+    nodes: Arc<Nodes>,
+) -> Result<Scanner, DiscoveryError> {
+    info!("DNS discovery config: {:?}", config);
+
+    if domains.is_empty() {
+        return Err(DiscoveryError::InvalidConfig("no DNS domains given".into()));
+    }
+
+    let backend = match source {
+        DnsSource::Resolvers(addrs) if addrs.is_empty() => {
+            return Err(DiscoveryError::InvalidConfig(
+                "no DNS resolvers given".into(),
+            ));
+        }
+        DnsSource::Resolvers(addrs) => Backend::resolvers(&addrs)
+            .await
+            .map_err(DiscoveryError::Resolver)?,
+        DnsSource::File(path) => Backend::File(HostsFile::new(path)),
+    };
+
+    let confirmer = match config.confirm_with {
+        Some(addr) => {
+            let socket = UdpSocket::bind(bind_addr_for(&addr))
+                .await
+                .map_err(DiscoveryError::Resolver)?;
+            socket
+                .connect(addr)
+                .await
+                .map_err(DiscoveryError::Resolver)?;
+            Some((addr, socket))
+        }
+        None => None,
+    };
+
+    let mut found_own_ip = false;
+    for interface in interfaces {
+        for ip in get_ipv4s(&interface) {
+            nodes.add_own_ip(ip);
+            found_own_ip = true;
+            info!("added own public ip {} to ignore list", ip);
+        }
+    }
+    // without our real address in the ignore list DNS hands us back to
+    // ourselves, so fall back to every address we have
+    if !found_own_ip {
+        for ip in local_ips() {
+            nodes.add_own_ip(ip);
+            info!("added local ip {} to ignore list", ip);
+        }
+    }
+
+    for (ip, tag, seq) in &config.seed_nodes {
+        nodes.insert_unchecked(*ip, tag.clone(), *seq);
+    }
+
+    let rng = match config.rng_seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    let (prefix, tags, srv) = match names {
+        Names::Tags { prefix, tags } => (prefix, tags, None),
+        Names::Srv { name, service } => (String::new(), Vec::new(), Some((name, service))),
+    };
+    Ok(Scanner {
+        backend,
+        confirmer,
+        domains,
+        prefix,
+        tags,
+        srv,
+        nodes,
+        config,
+        rng,
+        empty_scans: 0,
+        degraded: Arc::new(AtomicBool::new(false)),
+    })
+}
+
+/// The state a scan works from, owned by the re-check task between scans.
+struct Scanner {
+    backend: Backend,
+    confirmer: Option<(SocketAddr, UdpSocket)>,
+    domains: Vec<String>,
+    prefix: String,
+    tags: Vec<String>,
+    /// SRV name and the service it's for, in place of the tags.
+    srv: Option<(String, String)>,
+    nodes: Arc<Nodes>,
+    config: DnsConfig,
+    rng: StdRng,
+    /// Scans in a row that resolved nothing.
+    empty_scans: u32,
+    degraded: Arc<AtomicBool>,
+}
+
+impl Scanner {
+    /// One scan, in a span carrying the domain and prefix and, once it ends,
+    /// the node counts. Returns the wait until the next.
+    async fn perform_dns_checks(&mut self) -> Duration {
+        let span = info_span!(
+            "dns_check",
+            domain = %self.domains.join(","),
+            prefix = %self.prefix,
+            added = field::Empty,
+            updated = field::Empty,
+            total = field::Empty,
+        );
+        self.scan().instrument(span).await
+    }
+
+    async fn scan(&mut self) -> Duration {
+        if let Backend::File(hosts) = &mut self.backend {
+            if let Err(e) = hosts.refresh().await {
+                warn!("Failed to read {}: {}", hosts.path.display(), e);
+            }
+        }
+
+        let mut scan = Scan {
+            before: self.nodes.all().iter().map(|node| node.ip()).collect(),
+            resolved: HashSet::new(),
+            claimed: HashMap::new(),
+            summary: ScanSummary::default(),
+            min_ttl: None,
+        };
+
+        for (domain, tag) in self.domain_tags() {
+            scan.summary.per_tag.entry(tag.clone()).or_default();
+            let subdomains: Vec<(u32, String)> = (1..=self.config.max_seq)
+                .map(|seq| (seq, format!("{}-{}-{}", self.prefix, tag, seq)))
+                .collect();
+
+            if let Some(addr) = self.concurrent_resolver() {
+                let queries: Vec<(String, u16)> = subdomains
+                    .iter()
+                    .map(|(_, subdomain)| (format!("{}.{}", subdomain, domain), self.rng.gen()))
+                    .collect();
+                let mut results = resolve_concurrently(addr, queries.clone(), &self.config).await;
+                scan.summary.queries += results.len();
+                // a resolver that is down fails every query in the batch, so
+                // move on to the others in turn, as a single lookup would
+                for _ in 1..self.backend.resolver_count() {
+                    if !results.iter().all(Result::is_err) {
+                        break;
+                    }
+                    let Some(next) = self.backend.fail_over() else {
+                        break;
+                    };
+                    results = resolve_concurrently(next, queries.clone(), &self.config).await;
+                    scan.summary.queries += results.len();
+                }
+                for ((seq, subdomain), result) in subdomains.into_iter().zip(results) {
+                    match result {
+                        Ok(Some(resolved)) => {
+                            scan.observe_ttl(resolved.ttl);
+                            let name = (domain.as_str(), subdomain.as_str());
+                            self.record(&mut scan, &tag, seq, name, resolved.ip).await
+                        }
+                        Ok(None) => {
+                            let name = (domain.as_str(), subdomain.as_str());
+                            self.record_missing(&mut scan, &tag, seq, name).await;
+                        }
+                        Err(e) => {
+                            error!("Error querying {}: {}", subdomain, e);
+                            scan.summary.errors += 1;
+                        }
                     }
+                }
+                continue;
+            }
 
-                    let is_self = own_ips.contains(&ip);
-                    // always add to update last seen
-                    nodes.add(ip.to_owned(), Some(tag.to_owned()), Some(seq), is_self);
+            for (seq, subdomain) in subdomains {
+                scan.summary.queries += 1;
+                let id = self.rng.gen();
+                match self
+                    .backend
+                    .lookup(&domain, &subdomain, &self.config, id)
+                    .await
+                {
+                    Ok(Some(resolved)) => {
+                        scan.observe_ttl(resolved.ttl);
+                        let name = (domain.as_str(), subdomain.as_str());
+                        self.record(&mut scan, &tag, seq, name, resolved.ip).await
+                    }
+                    Ok(None) => {
+                        let name = (domain.as_str(), subdomain.as_str());
+                        if !self.record_missing(&mut scan, &tag, seq, name).await
+                            && self.config.stop_on_gap
+                        {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        error!("Error querying {}: {}", subdomain, e);
+                        scan.summary.errors += 1;
+                        break;
+                    }
                 }
-                Ok(None) => {
-                    info!("No DNS results subdomain={} domain={}", subdomain, domain);
-                    break;
+            }
+        }
+
+        if let Some((name, service)) = self.srv.clone() {
+            self.scan_srv(&mut scan, &name, &service).await;
+        }
+
+        if scan.resolved.is_empty() && scan.summary.ipv6_only == 0 {
+            self.empty_scans += 1;
+        } else {
+            self.empty_scans = 0;
+        }
+        let degraded =
+            self.config.degraded_after > 0 && self.empty_scans >= self.config.degraded_after;
+        if self.degraded.swap(degraded, Ordering::Relaxed) != degraded {
+            if degraded {
+                warn!(
+                    "{} consecutive DNS scans resolved nothing, marking session degraded",
+                    self.empty_scans
+                );
+            } else {
+                info!("DNS scan resolved nodes again, leaving degraded state");
+            }
+            self.nodes.suspend_reaping(degraded);
+        }
+
+        Span::current()
+            .record("added", scan.summary.added.len())
+            .record("updated", scan.summary.updated)
+            .record("total", self.nodes.len());
+
+        let min_ttl = scan.min_ttl;
+        if let Some(on_scan_complete) = &self.config.on_scan_complete {
+            let mut summary = scan.summary;
+            summary.removed = scan.before.difference(&scan.resolved).copied().collect();
+            on_scan_complete(&self.nodes, summary);
+        }
+
+        next_check_in(&self.config, min_ttl)
+    }
+
+    /// The resolver to probe a whole tag at once with, when
+    /// [`DnsConfig::concurrency`] allows it. A batch that fails outright
+    /// fails over to the next resolver, as [`Backend::lookup`] does.
+    fn concurrent_resolver(&self) -> Option<SocketAddr> {
+        match &self.backend {
+            Backend::Resolvers { .. } if self.config.concurrency > 1 => {
+                self.backend.active().map(|resolver| resolver.addr)
+            }
+            _ => None,
+        }
+    }
+
+    /// Every domain paired with every tag, domain by domain.
+    fn domain_tags(&self) -> Vec<(String, String)> {
+        self.domains
+            .iter()
+            .flat_map(|domain| {
+                self.tags
+                    .iter()
+                    .map(move |tag| (domain.clone(), tag.clone()))
+            })
+            .collect()
+    }
+
+    /// Confirms and adds a node resolved for `subdomain` under `domain`,
+    /// given as `(domain, subdomain)`.
+    async fn record(
+        &mut self,
+        scan: &mut Scan,
+        tag: &str,
+        seq: u32,
+        (domain, subdomain): (&str, &str),
+        ip: Ipv4Addr,
+    ) {
+        // the first name to resolve to an address this scan keeps it, so an
+        // address under two names doesn't flip between them every scan
+        let name = format!("{}.{}", subdomain, domain);
+        if let Some(claimant) = scan.claimed.get(&ip) {
+            warn!(
+                "{} resolved to {}, which {} already claimed this scan; ignoring it",
+                name, ip, claimant
+            );
+            return;
+        }
+
+        if let Some((addr, socket)) = &self.confirmer {
+            let id = self.rng.gen();
+            match get_dns(
+                *addr,
+                domain.to_string(),
+                socket,
+                subdomain.to_string(),
+                &self.config,
+                id,
+            )
+            .await
+            {
+                Ok(Some(confirmed)) if confirmed.ip == ip => {}
+                Ok(confirmed) => {
+                    warn!(
+                        "{} resolved to {} but {} says {:?}; possible poisoning or resolver desync",
+                        subdomain,
+                        ip,
+                        addr,
+                        confirmed.map(|confirmed| confirmed.ip)
+                    );
+                    scan.summary.mismatches += 1;
+                    return;
                 }
                 Err(e) => {
-                    eprintln!("Error querying {}: {}", subdomain, e);
-                    break;
+                    warn!("Error confirming {} with {}: {}", subdomain, addr, e);
+                    scan.summary.errors += 1;
+                    return;
+                }
+            }
+        }
+        // only a confirmed name claims the address
+        scan.claimed.insert(ip, name);
+
+        let nodes = &self.nodes;
+        let is_self = nodes.is_own_ip(&ip);
+        if !nodes.contains(&ip) && !is_self {
+            info!("Discovered new node via DNS: {}", ip);
+        }
+        if scan.resolved.insert(ip) && !scan.before.contains(&ip) {
+            scan.summary.added.push(ip);
+        }
+        let counts = scan.summary.per_tag.entry(tag.to_owned()).or_default();
+        if is_self {
+            counts.own += 1;
+        } else {
+            counts.discovered += 1;
+        }
+
+        // always add to update last seen
+        if nodes.add_resolved(ip, tag.to_owned(), seq, domain.to_owned()) == AddOutcome::Updated {
+            scan.summary.updated += 1;
+        }
+
+        if self.config.query_aaaa {
+            scan.summary.queries += 1;
+            let id = self.rng.gen();
+            match self
+                .backend
+                .lookup6(domain, subdomain, &self.config, id)
+                .await
+            {
+                Ok(ipv6_addrs) => {
+                    self.nodes.set_ipv6_addrs(&ip, ipv6_addrs);
+                }
+                Err(e) => {
+                    warn!("Error querying AAAA for {}: {}", subdomain, e);
+                    scan.summary.errors += 1;
+                }
+            }
+        }
+
+        if self.config.query_txt {
+            scan.summary.queries += 1;
+            let id = self.rng.gen();
+            match self
+                .backend
+                .lookup_txt(domain, subdomain, &self.config, id)
+                .await
+            {
+                Ok(metadata) => {
+                    self.nodes.set_metadata(&ip, metadata);
+                }
+                Err(e) => {
+                    warn!("Error querying TXT for {}: {}", subdomain, e);
+                    scan.summary.errors += 1;
+                }
+            }
+        }
+    }
+
+    /// Adds every target of the SRV records at `name`.
+    async fn scan_srv(&mut self, scan: &mut Scan, name: &str, service: &str) {
+        scan.summary.queries += 1;
+        let id = self.rng.gen();
+        let records = match self.backend.lookup_srv(name, &self.config, id).await {
+            Ok(records) => records,
+            Err(e) => {
+                warn!("Error querying SRV {}: {}", name, e);
+                scan.summary.errors += 1;
+                return;
+            }
+        };
+        if records.is_empty() {
+            info!("No SRV records at {}", name);
+        }
+
+        for record in records {
+            scan.observe_ttl(Some(record.ttl));
+            let ip = match record.ip {
+                Some(ip) => ip,
+                None => {
+                    scan.summary.queries += 1;
+                    let id = self.rng.gen();
+                    match self
+                        .backend
+                        .lookup_name(&record.target, &self.config, id)
+                        .await
+                    {
+                        Ok(Some(resolved)) => {
+                            scan.observe_ttl(resolved.ttl);
+                            resolved.ip
+                        }
+                        Ok(None) => {
+                            info!("SRV target {} has no A record", record.target);
+                            continue;
+                        }
+                        Err(e) => {
+                            warn!("Error resolving SRV target {}: {}", record.target, e);
+                            scan.summary.errors += 1;
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            let is_self = self.nodes.is_own_ip(&ip);
+            if !self.nodes.contains(&ip) && !is_self {
+                info!("Discovered new node via SRV: {}:{}", ip, record.port);
+            }
+            if scan.resolved.insert(ip) && !scan.before.contains(&ip) {
+                scan.summary.added.push(ip);
+            }
+            let counts = scan.summary.per_tag.entry(service.to_owned()).or_default();
+            if is_self {
+                counts.own += 1;
+            } else {
+                counts.discovered += 1;
+            }
+
+            if self.nodes.add(ip, Some(service.to_owned()), None) == AddOutcome::Updated {
+                scan.summary.updated += 1;
+            }
+            self.nodes
+                .set_srv(&ip, record.port, record.priority, record.weight);
+        }
+    }
+
+    /// Handles a name with no A record, given as `(domain, subdomain)`,
+    /// adding it as an IPv6-only node if it has AAAA records. Returns true if
+    /// it did, so isn't really a gap.
+    async fn record_missing(
+        &mut self,
+        scan: &mut Scan,
+        tag: &str,
+        seq: u32,
+        (domain, subdomain): (&str, &str),
+    ) -> bool {
+        if self.config.query_aaaa {
+            scan.summary.queries += 1;
+            let id = self.rng.gen();
+            match self
+                .backend
+                .lookup6(domain, subdomain, &self.config, id)
+                .await
+            {
+                Ok(ipv6_addrs) if !ipv6_addrs.is_empty() => {
+                    let (tag, domain) = (tag.to_owned(), domain.to_owned());
+                    if self.nodes.add_ipv6_only(ipv6_addrs, tag, seq, domain) {
+                        info!("Discovered new IPv6-only node via DNS: {}", subdomain);
+                    }
+                    scan.summary.ipv6_only += 1;
+                    return true;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Error querying AAAA for {}: {}", subdomain, e);
+                    scan.summary.errors += 1;
                 }
             }
         }
+
+        info!("No DNS results subdomain={} domain={}", subdomain, domain);
+        false
     }
 }
 
+/// What one pass of [`Scanner::perform_dns_checks`] has seen so far.
+struct Scan {
+    before: HashSet<Ipv4Addr>,
+    resolved: HashSet<Ipv4Addr>,
+    /// The name each address was first resolved from this scan.
+    claimed: HashMap<Ipv4Addr, String>,
+    summary: ScanSummary,
+    /// Shortest TTL among the answers so far.
+    min_ttl: Option<Duration>,
+}
+
+impl Scan {
+    fn observe_ttl(&mut self, ttl: Option<Duration>) {
+        self.min_ttl = self.min_ttl.into_iter().chain(ttl).min();
+    }
+}
+
+/// The wait before the next scan: the shortest TTL seen, held between
+/// [`DnsConfig::min_check_interval`] and [`DnsConfig::check_interval`], or
+/// the latter when no answer carried one.
+fn next_check_in(config: &DnsConfig, min_ttl: Option<Duration>) -> Duration {
+    let max = config.check_interval.max(config.min_check_interval);
+    match min_ttl {
+        Some(ttl) => ttl.clamp(config.min_check_interval, max),
+        None => config.check_interval,
+    }
+}
+
+/// An address a name resolved to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Resolved {
+    ip: Ipv4Addr,
+    /// How long the answer may be cached; `None` from a hosts file.
+    ttl: Option<Duration>,
+}
+
+/// Looks up every `(name, id)` at once, at most [`DnsConfig::concurrency`] in
+/// flight, returning the results in the order given. Each query gets its own
+/// socket so responses can't be read by the wrong waiter.
+async fn resolve_concurrently(
+    addr: SocketAddr,
+    queries: Vec<(String, u16)>,
+    config: &DnsConfig,
+) -> Vec<io::Result<Option<Resolved>>> {
+    let mut results: Vec<io::Result<Option<Resolved>>> = Vec::with_capacity(queries.len());
+    results.resize_with(queries.len(), || Ok(None));
+
+    let mut in_flight = JoinSet::new();
+    let mut queries = queries.into_iter().enumerate();
+    loop {
+        while in_flight.len() < config.concurrency {
+            let Some((index, (name, id))) = queries.next() else {
+                break;
+            };
+            let config = config.clone();
+            in_flight.spawn(async move {
+                let result: io::Result<Option<Resolved>> = async {
+                    let socket = UdpSocket::bind(bind_addr_for(&addr)).await?;
+                    socket.connect(addr).await?;
+                    resolve_a(&socket, &name, &config, id).await
+                }
+                .await;
+                (index, result)
+            });
+        }
+
+        match in_flight.join_next().await {
+            Some(Ok((index, result))) => results[index] = result,
+            Some(Err(e)) => error!("DNS probe task failed: {}", e),
+            None => break,
+        }
+    }
+    results
+}
+
+enum Backend {
+    Resolvers {
+        resolvers: Vec<Resolver>,
+        /// The resolver currently answering for us.
+        active: AtomicUsize,
+    },
+    File(HostsFile),
+}
+
+struct Resolver {
+    addr: SocketAddr,
+    socket: UdpSocket,
+}
+
+impl Backend {
+    async fn resolvers(addrs: &[SocketAddr]) -> io::Result<Self> {
+        let mut resolvers = Vec::with_capacity(addrs.len());
+        for &addr in addrs {
+            let socket = UdpSocket::bind(bind_addr_for(&addr)).await?;
+            socket.connect(addr).await?;
+            resolvers.push(Resolver { addr, socket });
+        }
+        Ok(Backend::Resolvers {
+            resolvers,
+            active: AtomicUsize::new(0),
+        })
+    }
+
+    fn active(&self) -> Option<&Resolver> {
+        match self {
+            Backend::Resolvers { resolvers, active } => {
+                resolvers.get(active.load(Ordering::Relaxed))
+            }
+            Backend::File(_) => None,
+        }
+    }
+
+    fn resolver_count(&self) -> usize {
+        match self {
+            Backend::Resolvers { resolvers, .. } => resolvers.len(),
+            Backend::File(_) => 0,
+        }
+    }
+
+    /// Makes the resolver after the active one active, returning its address,
+    /// or `None` if there is no other to move to.
+    fn fail_over(&self) -> Option<SocketAddr> {
+        let Backend::Resolvers { resolvers, active } = self else {
+            return None;
+        };
+        if resolvers.len() < 2 {
+            return None;
+        }
+        let index = (active.load(Ordering::Relaxed) + 1) % resolvers.len();
+        active.store(index, Ordering::Relaxed);
+        warn!("Failed over to DNS resolver {}", resolvers[index].addr);
+        Some(resolvers[index].addr)
+    }
+
+    /// Runs `ask` against the active resolver, failing over to the others in
+    /// turn if it doesn't answer even after retries. Whichever answers stays
+    /// active. `name` is only for the log.
+    async fn with_failover<'a, T, F, Fut>(&'a self, name: &str, ask: F) -> io::Result<T>
+    where
+        F: Fn(&'a Resolver) -> Fut,
+        Fut: Future<Output = io::Result<T>>,
+    {
+        let Backend::Resolvers { resolvers, active } = self else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no resolvers"));
+        };
+
+        let start = active.load(Ordering::Relaxed);
+        let mut last_error = None;
+        for offset in 0..resolvers.len() {
+            let index = (start + offset) % resolvers.len();
+            let resolver = &resolvers[index];
+            match ask(resolver).await {
+                Ok(result) => {
+                    if index != start {
+                        warn!("Failed over to DNS resolver {}", resolver.addr);
+                        active.store(index, Ordering::Relaxed);
+                    }
+                    return Ok(result);
+                }
+                Err(e) => {
+                    warn!("DNS resolver {} failed for {}: {}", resolver.addr, name, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no resolvers")))
+    }
+
+    /// The A record for the name, failing over as [`with_failover`]
+    /// describes.
+    ///
+    /// [`with_failover`]: Self::with_failover
+    async fn lookup(
+        &self,
+        domain: &str,
+        subdomain: &str,
+        config: &DnsConfig,
+        id: u16,
+    ) -> io::Result<Option<Resolved>> {
+        if let Backend::File(hosts) = self {
+            let ip = hosts.lookup(domain, subdomain);
+            return Ok(ip.map(|ip| Resolved { ip, ttl: None }));
+        }
+        self.with_failover(subdomain, |resolver| {
+            get_dns(
+                resolver.addr,
+                domain.to_string(),
+                &resolver.socket,
+                subdomain.to_string(),
+                config,
+                id,
+            )
+        })
+        .await
+    }
+
+    /// AAAA records for the name. Hosts files only carry IPv4 addresses.
+    async fn lookup6(
+        &self,
+        domain: &str,
+        subdomain: &str,
+        config: &DnsConfig,
+        id: u16,
+    ) -> io::Result<Vec<Ipv6Addr>> {
+        if let Backend::File(_) = self {
+            return Ok(Vec::new());
+        }
+        let name = format!("{}.{}", subdomain, domain);
+        let answer = self
+            .with_failover(&name, |resolver| {
+                query(&resolver.socket, &name, Type::AAAA, config, id)
+            })
+            .await?;
+        Ok(answer
+            .answers
+            .iter()
+            .filter_map(|r| match r.resource {
+                Resource::AAAA(ip) if !ip.is_loopback() => Some(ip),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// The SRV records at `name`, lowest priority value first, each with its
+    /// target's address if the resolver sent it along.
+    async fn lookup_srv(
+        &self,
+        name: &str,
+        config: &DnsConfig,
+        id: u16,
+    ) -> io::Result<Vec<SrvRecord>> {
+        // hosts files have no SRV records
+        if let Backend::File(_) = self {
+            return Ok(Vec::new());
+        }
+        let answer = self
+            .with_failover(name, |resolver| {
+                query(&resolver.socket, name, Type::SRV, config, id)
+            })
+            .await?;
+        let mut records: Vec<SrvRecord> = answer
+            .answers
+            .iter()
+            .filter_map(|r| match &r.resource {
+                Resource::SRV(srv) => Some(SrvRecord {
+                    ttl: r.ttl,
+                    priority: srv.priority,
+                    weight: srv.weight,
+                    port: srv.port,
+                    target: srv.name.clone(),
+                    ip: glue_a_record(&answer, &srv.name),
+                }),
+                _ => None,
+            })
+            .collect();
+        records.sort_by_key(|record| (record.priority, std::cmp::Reverse(record.weight)));
+        Ok(records)
+    }
+
+    /// The A record for a fully qualified `name`.
+    async fn lookup_name(
+        &self,
+        name: &str,
+        config: &DnsConfig,
+        id: u16,
+    ) -> io::Result<Option<Resolved>> {
+        if let Backend::File(_) = self {
+            return Ok(None);
+        }
+        self.with_failover(name, |resolver| {
+            resolve_a(&resolver.socket, name, config, id)
+        })
+        .await
+    }
+
+    /// The `key=value` pairs in the TXT records at a name, empty if it has
+    /// none.
+    async fn lookup_txt(
+        &self,
+        domain: &str,
+        subdomain: &str,
+        config: &DnsConfig,
+        id: u16,
+    ) -> io::Result<HashMap<String, String>> {
+        if let Backend::File(_) = self {
+            return Ok(HashMap::new());
+        }
+        let name = format!("{}.{}", subdomain, domain);
+        let answer = self
+            .with_failover(&name, |resolver| {
+                query(&resolver.socket, &name, Type::TXT, config, id)
+            })
+            .await?;
+        let strings = answer
+            .answers
+            .iter()
+            .filter_map(|r| match &r.resource {
+                Resource::TXT(txt) => Some(&txt.0),
+                _ => None,
+            })
+            .flatten();
+        Ok(parse_txt_metadata(strings))
+    }
+}
+
+struct SrvRecord {
+    ttl: Duration,
+    priority: u16,
+    weight: u16,
+    port: u16,
+    target: String,
+    ip: Option<Ipv4Addr>,
+}
+
+/// Collects `key=value` strings, skipping any that aren't UTF-8 or have no
+/// `=` or an empty key. A later duplicate key wins.
+fn parse_txt_metadata<'a>(strings: impl Iterator<Item = &'a Vec<u8>>) -> HashMap<String, String> {
+    strings
+        .filter_map(|s| std::str::from_utf8(s).ok())
+        .filter_map(|s| s.split_once('='))
+        .filter(|(key, _)| !key.trim().is_empty())
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+/// The records of a [`DnsSource::File`], as of its last modification.
+struct HostsFile {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    records: HashMap<String, Ipv4Addr>,
+}
+
+impl HostsFile {
+    fn new(path: PathBuf) -> Self {
+        HostsFile {
+            path,
+            modified: None,
+            records: HashMap::new(),
+        }
+    }
+
+    /// Re-reads the file unless its modification time is unchanged since the
+    /// last read.
+    async fn refresh(&mut self) -> io::Result<()> {
+        let modified = fs::metadata(&self.path).await?.modified().ok();
+        if modified.is_some() && modified == self.modified {
+            return Ok(());
+        }
+        let contents = fs::read_to_string(&self.path).await?;
+        self.records = parse_hosts(&contents);
+        self.modified = modified;
+        info!(
+            "Loaded {} records from {}",
+            self.records.len(),
+            self.path.display()
+        );
+        Ok(())
+    }
+
+    fn lookup(&self, domain: &str, subdomain: &str) -> Option<Ipv4Addr> {
+        self.records
+            .get(&normalize_name(&format!("{}.{}", subdomain, domain)))
+            .or_else(|| self.records.get(&normalize_name(subdomain)))
+            .copied()
+    }
+}
+
+fn parse_hosts(contents: &str) -> HashMap<String, Ipv4Addr> {
+    let mut records = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next().map(str::parse::<Ipv4Addr>)) {
+            (Some(name), Some(Ok(ip))) => {
+                records.insert(normalize_name(name), ip);
+            }
+            _ => warn!("Skipping malformed line {}: {:?}", number + 1, line),
+        }
+    }
+    records
+}
+
 async fn get_dns(
     dns_service: SocketAddr,
     domain: String,
     socket: &UdpSocket,
     subdomain: String,
-) -> io::Result<Option<Ipv4Addr>> {
+    config: &DnsConfig,
+    id: u16,
+) -> io::Result<Option<Resolved>> {
+    let name = format!("{}.{}", subdomain, domain);
+    debug!("Querying {} for {}", dns_service, name);
+    resolve_a(socket, &name, config, id).await
+}
+
+/// Sends one question and waits for the response carrying its `id`.
+async fn query(
+    socket: &UdpSocket,
+    name: &str,
+    qtype: Type,
+    config: &DnsConfig,
+    id: u16,
+) -> io::Result<Message> {
+    let result = ask(socket, name, qtype, config, id).await;
+    stats::dns_query(&result);
+    result
+}
+
+/// [`query`] without the bookkeeping: the question, its retries and the TCP
+/// fallback.
+async fn ask(
+    socket: &UdpSocket,
+    name: &str,
+    qtype: Type,
+    config: &DnsConfig,
+    id: u16,
+) -> io::Result<Message> {
     let mut m = Message::default();
-    m.add_question(
-        &format!("{}.{}", subdomain, domain),
-        Type::A,
-        Class::Internet,
-    );
+    m.id = id;
+    m.add_question(name, qtype, Class::Internet);
     m.add_extension(Extension {
-        payload_size: 4096,
+        payload_size: config.edns_payload_size,
         ..Default::default()
     });
 
     let question = m.to_vec()?;
-    socket.send(&question).await?;
 
-    let mut resp = [0; 4096];
-    let len = timeout(Duration::new(5, 0), socket.recv(&mut resp)).await??;
+    // only silence and socket errors are retried; an answer, even one saying
+    // the name doesn't exist, is final
+    let mut backoff = config.base_backoff;
+    let mut attempt = 0;
+    loop {
+        match exchange(socket, &question, config, id).await {
+            Err(e) if attempt < config.retries => {
+                attempt += 1;
+                debug!(
+                    "No answer for {} ({}), retry {} of {} in {:?}",
+                    name, e, attempt, config.retries, backoff
+                );
+                sleep(backoff).await;
+                backoff *= 2;
+            }
+            Ok(answer) if answer.tc => {
+                debug!("Truncated answer for {}, retrying over TCP", name);
+                let addr = socket.peer_addr()?;
+                return exchange_tcp(addr, &question, config, id).await;
+            }
+            result => return result,
+        }
+    }
+}
 
-    let answer = Message::from_slice(&resp[0..len])?;
+/// Like [`exchange`], but over TCP with the two-byte length prefix, for
+/// answers too big for a datagram.
+async fn exchange_tcp(
+    addr: SocketAddr,
+    question: &[u8],
+    config: &DnsConfig,
+    id: u16,
+) -> io::Result<Message> {
+    let deadline = Instant::now() + config.query_timeout;
+    timeout_at(deadline, async {
+        let mut stream = TcpStream::connect(addr).await?;
+        let len = u16::try_from(question.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "query too long"))?;
+        let mut framed = Vec::with_capacity(question.len() + 2);
+        framed.extend_from_slice(&len.to_be_bytes());
+        framed.extend_from_slice(question);
+        stream.write_all(&framed).await?;
 
-    for r in answer.answers {
-        if let Resource::A(ip) = r.resource {
-            if !ip.is_loopback() {
-                return Ok(Some(ip.into()));
+        let mut len = [0; 2];
+        stream.read_exact(&mut len).await?;
+        let mut resp = vec![0; usize::from(u16::from_be_bytes(len))];
+        stream.read_exact(&mut resp).await?;
+        match Message::from_slice(&resp) {
+            Ok(answer) if answer.id == id => Ok(answer),
+            Ok(answer) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("TCP answer id={} awaiting id={}", answer.id, id),
+            )),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+        }
+    })
+    .await?
+}
+
+/// Sends `question` and reads until the response with `id` turns up or
+/// [`DnsConfig::query_timeout`] passes.
+async fn exchange(
+    socket: &UdpSocket,
+    question: &[u8],
+    config: &DnsConfig,
+    id: u16,
+) -> io::Result<Message> {
+    socket.send(question).await?;
+
+    // a resolver may still answer with up to 512 bytes whatever we advertise
+    let mut resp = vec![0; usize::from(config.edns_payload_size).max(512)];
+    let deadline = Instant::now() + config.query_timeout;
+    // keep reading until the response to this query turns up; anything else
+    // is a late answer to an earlier query or noise
+    loop {
+        let len = timeout_at(deadline, socket.recv(&mut resp)).await??;
+        match Message::from_slice(&resp[0..len]) {
+            Ok(answer) if answer.id == id => return Ok(answer),
+            Ok(answer) => {
+                debug!(
+                    "Discarding DNS response id={} awaiting id={}",
+                    answer.id, id
+                );
+            }
+            Err(e) => {
+                debug!("Discarding unparseable DNS response: {}", e);
             }
         }
     }
-
-    Ok(None)
 }
 
-pub fn get_ip(interface: &str) -> Option<Ipv4Addr> {
-    let addrs = match get_if_addrs() {
-        Ok(addrs) => addrs,
-        Err(e) => {
-            warn!("Failed to get network interfaces: {}", e);
-            return None;
+/// The A record `name` resolves to in `answer`, following any CNAMEs in the
+/// answer section. With `use_additional`, glue in the additional and
+/// authority sections counts too.
+fn first_a_record(answer: &Message, name: &str, use_additional: bool) -> Option<Ipv4Addr> {
+    let chain = cname_chain(answer, name);
+    let a_for_chain = |r: &Record| match r.resource {
+        Resource::A(ip) if !ip.is_loopback() && chain.contains(&normalize_name(&r.name)) => {
+            Some(ip)
         }
+        _ => None,
     };
 
-    for addr in addrs {
-        if addr.name == interface {
-            if let IpAddr::V4(ip) = addr.ip() {
-                return Some(ip);
+    if let Some(ip) = answer.answers.iter().find_map(a_for_chain) {
+        return Some(ip);
+    }
+    if !use_additional {
+        return None;
+    }
+    answer
+        .additionals
+        .iter()
+        .chain(answer.authoritys.iter())
+        .find_map(a_for_chain)
+}
+
+/// `name` and the CNAMEs it leads to through the answer section, in order,
+/// so the last entry is the name that should hold the A record. Stops at
+/// [`MAX_CNAME_HOPS`] or a loop.
+fn cname_chain(answer: &Message, name: &str) -> Vec<String> {
+    let mut chain = vec![normalize_name(name)];
+    while chain.len() <= MAX_CNAME_HOPS {
+        let current = &chain[chain.len() - 1];
+        let target = answer.answers.iter().find_map(|r| match &r.resource {
+            Resource::CNAME(target) if normalize_name(&r.name) == *current => {
+                Some(normalize_name(target))
             }
+            _ => None,
+        });
+        match target {
+            Some(target) if !chain.contains(&target) => chain.push(target),
+            _ => break,
         }
     }
+    chain
+}
 
-    None
+/// The A record for `name`. When the answer ends in a CNAME whose target
+/// the resolver didn't include, the target is queried in turn.
+async fn resolve_a(
+    socket: &UdpSocket,
+    name: &str,
+    config: &DnsConfig,
+    id: u16,
+) -> io::Result<Option<Resolved>> {
+    let mut name = name.to_string();
+    let mut ttl = None;
+    for hop in 0..=MAX_CNAME_HOPS {
+        let answer = query(socket, &name, Type::A, config, id.wrapping_add(hop as u16)).await?;
+        ttl = ttl.into_iter().chain(answer_ttl(&answer)).min();
+        if let Some(ip) = first_a_record(&answer, &name, config.use_additional_records) {
+            return Ok(Some(Resolved { ip, ttl }));
+        }
+        let mut chain = cname_chain(&answer, &name);
+        if chain.len() == 1 {
+            return Ok(None);
+        }
+        let target = chain.pop().unwrap();
+        debug!(
+            "{} is an alias for {}, which wasn't glued; querying it",
+            name, target
+        );
+        name = target;
+    }
+    warn!("Gave up resolving {} after {} CNAMEs", name, MAX_CNAME_HOPS);
+    Ok(None)
+}
+
+/// The shortest TTL in the answer section, CNAMEs included, since the
+/// answer is only good while every link in it is.
+fn answer_ttl(answer: &Message) -> Option<Duration> {
+    answer.answers.iter().map(|r| r.ttl).min()
+}
+
+/// An A record for `name` in the additional section, as resolvers include
+/// for SRV targets.
+fn glue_a_record(answer: &Message, name: &str) -> Option<Ipv4Addr> {
+    let name = normalize_name(name);
+    answer.additionals.iter().find_map(|r| match r.resource {
+        Resource::A(ip) if !ip.is_loopback() && normalize_name(&r.name) == name => Some(ip),
+        _ => None,
+    })
+}
+
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// An ephemeral local address in the same family as `resolver`, so an
+/// IPv6-only resolver can be reached.
+fn bind_addr_for(resolver: &SocketAddr) -> SocketAddr {
+    match resolver {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::NodeEvent;
+    use std::sync::Mutex;
 
     #[tokio::test]
     async fn test_udp() {
@@ -184,4 +1587,686 @@ mod tests {
 
         let addr: SocketAddr = ([8, 8, 8, 8], 53).into();
     }
+
+    #[test]
+    fn test_hosts_file() {
+        let records = parse_hosts(
+            "# static peers\n\
+             live-uk-lon-1.wavey.io 10.0.0.1\n\
+             LIVE-uk-lon-2 10.0.0.2  # no domain\n\
+             \n\
+             live-uk-lon-3 not-an-ip\n",
+        );
+        assert_eq!(records.len(), 2);
+
+        let hosts = HostsFile {
+            path: PathBuf::new(),
+            modified: None,
+            records,
+        };
+        assert_eq!(
+            hosts.lookup("wavey.io", "live-uk-lon-1"),
+            Some(Ipv4Addr::new(10, 0, 0, 1))
+        );
+        assert_eq!(
+            hosts.lookup("wavey.io", "live-uk-lon-2"),
+            Some(Ipv4Addr::new(10, 0, 0, 2))
+        );
+        assert_eq!(hosts.lookup("wavey.io", "live-uk-lon-3"), None);
+    }
+
+    fn file_scanner(records: &str, config: DnsConfig) -> Scanner {
+        Scanner {
+            backend: Backend::File(HostsFile {
+                path: PathBuf::from("/nonexistent"),
+                modified: None,
+                records: parse_hosts(records),
+            }),
+            confirmer: None,
+            domains: vec!["wavey.io".into()],
+            prefix: "live".into(),
+            tags: vec!["uk-lon".into()],
+            srv: None,
+            nodes: Arc::new(Nodes::new()),
+            config,
+            rng: StdRng::seed_from_u64(0),
+            empty_scans: 0,
+            degraded: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_multiple_domains() {
+        // 10.0.0.5 is seq 2 under dc1 but seq 3 under dc2
+        let records = "live-uk-lon-1.dc1.wavey.io 10.0.0.1\n\
+                       live-uk-lon-2.dc1.wavey.io 10.0.0.5\n\
+                       live-uk-lon-1.dc2.wavey.io 10.1.0.1\n\
+                       live-uk-lon-2.dc2.wavey.io 10.0.0.9\n\
+                       live-uk-lon-3.dc2.wavey.io 10.0.0.5\n";
+        let summaries = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&summaries);
+        let mut scanner = file_scanner(
+            records,
+            DnsConfig {
+                on_scan_complete: Some(Arc::new(move |_, summary| {
+                    seen.lock().unwrap().push(summary)
+                })),
+                ..Default::default()
+            },
+        );
+        scanner.domains = vec!["dc1.wavey.io".into(), "dc2.wavey.io".into()];
+        scanner.nodes.add_own_ip(Ipv4Addr::new(10, 0, 0, 9));
+        let mut events = scanner.nodes.events();
+        scanner.perform_dns_checks().await;
+
+        let nodes = scanner.nodes.ordered();
+        let ips: Vec<Ipv4Addr> = nodes.iter().map(Node::ip).collect();
+        assert_eq!(
+            ips,
+            [[10, 0, 0, 1], [10, 0, 0, 5], [10, 0, 0, 9], [10, 1, 0, 1]].map(Ipv4Addr::from)
+        );
+        assert_eq!(nodes[0].domain(), Some("dc1.wavey.io"));
+        // under both domains: one node, kept by the first to claim it
+        assert_eq!(nodes[1].domain(), Some("dc1.wavey.io"));
+        assert_eq!(nodes[1].seq(), Some(2));
+        assert!(nodes[2].is_self());
+        assert_eq!(nodes[3].domain(), Some("dc2.wavey.io"));
+
+        // joiners are announced with their domain already set
+        let mut joined = 0;
+        while let Ok(event) = events.try_recv() {
+            if let NodeEvent::Joined(node) = event {
+                assert!(node.domain().is_some(), "{:?}", node);
+                joined += 1;
+            }
+        }
+        assert_eq!(joined, 4);
+
+        // and rescanning doesn't flip the shared node between its claims
+        scanner.perform_dns_checks().await;
+        assert!(events.try_recv().is_err());
+        assert_eq!(
+            scanner
+                .nodes
+                .get(&Ipv4Addr::new(10, 0, 0, 5))
+                .unwrap()
+                .seq(),
+            Some(2)
+        );
+
+        let summaries = summaries.lock().unwrap();
+        assert_eq!(summaries[0].added.len(), 4);
+        assert_eq!(summaries[0].per_tag["uk-lon"].own, 1);
+        assert_eq!(summaries[1].updated, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_nodes_outlive_check_interval() {
+        let mut scanner = file_scanner("live-uk-lon-1 10.0.0.1\n", DnsConfig::default());
+        scanner.nodes = Arc::new(nodes_for(&scanner.config));
+        let check_interval = scanner.config.check_interval;
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+        scanner.perform_dns_checks().await;
+
+        // long past a VLAN silence window, and nothing refreshes a DNS node
+        // between checks
+        tokio::time::advance(crate::SILENCE_WINDOW * 2).await;
+        scanner.nodes.reap();
+        assert!(scanner.nodes.contains(&ip));
+
+        // reaped once per check, as the reaper task does, then rescanned
+        for _ in 0..3 {
+            tokio::time::advance(check_interval).await;
+            scanner.nodes.reap();
+            assert!(scanner.nodes.contains(&ip));
+            scanner.perform_dns_checks().await;
+        }
+
+        // only a node missing from every check for the whole window goes
+        tokio::time::advance(scanner.nodes.silence_window() + Duration::from_secs(1)).await;
+        scanner.nodes.reap();
+        assert!(!scanner.nodes.contains(&ip));
+    }
+
+    #[tokio::test]
+    async fn test_discover_once() {
+        let path = std::env::temp_dir().join(format!("once-hosts-{}", std::process::id()));
+        tokio::fs::write(
+            &path,
+            "live-uk-lon-2.wavey.io 10.0.0.2\n\
+             live-uk-lon-1.wavey.io 10.0.0.1\n",
+        )
+        .await
+        .unwrap();
+
+        let nodes = discover_once(
+            Vec::<String>::new(),
+            DnsSource::File(path.clone()),
+            ["wavey.io"],
+            "live".into(),
+            vec!["uk-lon".into()],
+            DnsConfig::default(),
+        )
+        .await
+        .unwrap();
+        let ips: Vec<Ipv4Addr> = nodes.iter().map(Node::ip).collect();
+        assert_eq!(ips, [[10, 0, 0, 1], [10, 0, 0, 2]].map(Ipv4Addr::from));
+
+        assert!(matches!(
+            discover_once(
+                Vec::<String>::new(),
+                DnsSource::File(path.clone()),
+                Vec::<String>::new(),
+                "live".into(),
+                vec!["uk-lon".into()],
+                DnsConfig::default(),
+            )
+            .await,
+            Err(DiscoveryError::InvalidConfig(_))
+        ));
+        let _ = tokio::fs::remove_file(&path).await;
+    }
+
+    #[tokio::test]
+    async fn test_scan_past_gap() {
+        let records = "live-uk-lon-1 10.0.0.1\n\
+                       live-uk-lon-2 10.0.0.2\n\
+                       live-uk-lon-4 10.0.0.4\n";
+
+        let mut scanner = file_scanner(records, DnsConfig::default());
+        scanner.perform_dns_checks().await;
+        assert_eq!(scanner.nodes.all().len(), 2);
+
+        let mut scanner = file_scanner(
+            records,
+            DnsConfig {
+                max_seq: 10,
+                stop_on_gap: false,
+                ..Default::default()
+            },
+        );
+        scanner.perform_dns_checks().await;
+        let ips: Vec<Ipv4Addr> = scanner.nodes.ordered().iter().map(|n| n.ip()).collect();
+        assert_eq!(ips, [1, 2, 4].map(|last| Ipv4Addr::new(10, 0, 0, last)));
+        assert_eq!(scanner.nodes.all()[2].seq(), Some(4));
+
+        let mut scanner = file_scanner(
+            records,
+            DnsConfig {
+                max_seq: 3,
+                stop_on_gap: false,
+                ..Default::default()
+            },
+        );
+        scanner.perform_dns_checks().await;
+        assert_eq!(scanner.nodes.all().len(), 2);
+    }
+
+    #[test]
+    fn test_parse_txt_metadata() {
+        let strings: Vec<Vec<u8>> = vec![
+            b"region=eu-west".to_vec(),
+            b"capacity = 40".to_vec(),
+            b"no-equals".to_vec(),
+            b"=empty-key".to_vec(),
+            vec![0xff, b'=', b'x'],
+            b"version=1.2=beta".to_vec(),
+        ];
+        let metadata = parse_txt_metadata(strings.iter());
+        assert_eq!(metadata.len(), 3);
+        assert_eq!(metadata["region"], "eu-west");
+        assert_eq!(metadata["capacity"], "40");
+        assert_eq!(metadata["version"], "1.2=beta");
+
+        assert!(parse_txt_metadata(std::iter::empty()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_per_tag_counts() {
+        let summaries = Arc::new(Mutex::new(Vec::new()));
+        let summaries_clone = Arc::clone(&summaries);
+        let mut scanner = file_scanner(
+            "live-uk-lon-1 10.0.0.1\n\
+             live-uk-lon-2 10.0.0.2\n\
+             live-uk-lon-3 10.0.0.3\n",
+            DnsConfig {
+                on_scan_complete: Some(Arc::new(move |_, summary| {
+                    summaries_clone.lock().unwrap().push(summary);
+                })),
+                ..Default::default()
+            },
+        );
+        scanner.tags.push("us-nyc".into());
+        scanner.nodes.add_own_ip(Ipv4Addr::new(10, 0, 0, 2));
+        scanner.perform_dns_checks().await;
+
+        let summaries = summaries.lock().unwrap();
+        let per_tag = &summaries[0].per_tag;
+        assert_eq!(
+            per_tag["uk-lon"],
+            TagCounts {
+                discovered: 2,
+                own: 1
+            }
+        );
+        assert_eq!(per_tag["us-nyc"], TagCounts::default());
+    }
+
+    /// A resolver that answers every query, with no records, after `delay`.
+    async fn slow_resolver(delay: Duration) -> SocketAddr {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            while let Ok((len, src)) = socket.recv_from(&mut buf).await {
+                let mut response = buf[..len].to_vec();
+                response[2] |= 0x80; // QR: this is a response
+                let socket = Arc::clone(&socket);
+                tokio::spawn(async move {
+                    sleep(delay).await;
+                    let _ = socket.send_to(&response, src).await;
+                });
+            }
+        });
+        addr
+    }
+
+    /// A resolver that ignores the first `drop` queries and answers the rest
+    /// straight away.
+    async fn lossy_resolver(drop: usize) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            let mut seen = 0;
+            while let Ok((len, src)) = socket.recv_from(&mut buf).await {
+                seen += 1;
+                if seen > drop {
+                    buf[2] |= 0x80;
+                    let _ = socket.send_to(&buf[..len], src).await;
+                }
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_query_retries() {
+        let config = DnsConfig {
+            query_timeout: Duration::from_millis(50),
+            retries: 2,
+            base_backoff: Duration::from_millis(5),
+            ..Default::default()
+        };
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(lossy_resolver(2).await).await.unwrap();
+        let answer = query(&socket, "live-uk-lon-1.wavey.io", Type::A, &config, 7).await;
+        assert_eq!(answer.unwrap().id, 7);
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(lossy_resolver(3).await).await.unwrap();
+        let answer = query(&socket, "live-uk-lon-1.wavey.io", Type::A, &config, 7).await;
+        assert!(answer.is_err());
+    }
+
+    /// A resolver whose UDP answers are always truncated, and which answers
+    /// in full over TCP on the same port.
+    async fn truncating_resolver() -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket = UdpSocket::bind(addr).await.unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            while let Ok((len, src)) = socket.recv_from(&mut buf).await {
+                // QR and TC
+                buf[2] |= 0x82;
+                let _ = socket.send_to(&buf[..len], src).await;
+            }
+        });
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut len = [0; 2];
+                stream.read_exact(&mut len).await.unwrap();
+                let mut buf = vec![0; usize::from(u16::from_be_bytes(len))];
+                stream.read_exact(&mut buf).await.unwrap();
+                buf[2] |= 0x80;
+                stream.write_all(&len).await.unwrap();
+                stream.write_all(&buf).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_truncated_answer_retried_over_tcp() {
+        let config = DnsConfig {
+            query_timeout: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(truncating_resolver().await).await.unwrap();
+
+        let answer = query(&socket, "live-uk-lon-1.wavey.io", Type::A, &config, 9)
+            .await
+            .unwrap();
+        assert_eq!(answer.id, 9);
+        assert!(!answer.tc);
+    }
+
+    #[tokio::test]
+    async fn test_resolver_failover() {
+        let config = DnsConfig {
+            query_timeout: Duration::from_millis(50),
+            retries: 0,
+            ..Default::default()
+        };
+        let dead = lossy_resolver(usize::MAX).await;
+        let working = lossy_resolver(0).await;
+        let backend = Backend::resolvers(&[dead, working]).await.unwrap();
+
+        let result = backend
+            .lookup("wavey.io", "live-uk-lon-1", &config, 1)
+            .await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(backend.active().unwrap().addr, working);
+
+        // the working resolver stays active, so the next lookup is immediate
+        let started = Instant::now();
+        backend
+            .lookup("wavey.io", "live-uk-lon-2", &config, 2)
+            .await
+            .unwrap();
+        assert!(started.elapsed() < config.query_timeout);
+    }
+
+    #[tokio::test]
+    async fn test_txt_and_aaaa_fail_over() {
+        let config = DnsConfig {
+            query_timeout: Duration::from_millis(50),
+            retries: 0,
+            ..Default::default()
+        };
+        let dead = lossy_resolver(usize::MAX).await;
+        let working = lossy_resolver(0).await;
+
+        let backend = Backend::resolvers(&[dead, working]).await.unwrap();
+        let metadata = backend
+            .lookup_txt("wavey.io", "live-uk-lon-1", &config, 1)
+            .await;
+        assert!(metadata.unwrap().is_empty());
+        assert_eq!(backend.active().unwrap().addr, working);
+
+        let backend = Backend::resolvers(&[dead, working]).await.unwrap();
+        let ipv6_addrs = backend
+            .lookup6("wavey.io", "live-uk-lon-1", &config, 2)
+            .await;
+        assert!(ipv6_addrs.unwrap().is_empty());
+        assert_eq!(backend.active().unwrap().addr, working);
+    }
+
+    #[tokio::test]
+    async fn test_srv_fails_over() {
+        let config = DnsConfig {
+            query_timeout: Duration::from_millis(50),
+            retries: 0,
+            ..Default::default()
+        };
+        let dead = lossy_resolver(usize::MAX).await;
+        let working = lossy_resolver(0).await;
+
+        let backend = Backend::resolvers(&[dead, working]).await.unwrap();
+        let records = backend.lookup_srv("_api._tcp.wavey.io", &config, 1).await;
+        assert!(records.unwrap().is_empty());
+        assert_eq!(backend.active().unwrap().addr, working);
+
+        // unglued targets are looked up the same way
+        let backend = Backend::resolvers(&[dead, working]).await.unwrap();
+        let target = backend.lookup_name("api-1.wavey.io", &config, 2).await;
+        assert_eq!(target.unwrap(), None);
+        assert_eq!(backend.active().unwrap().addr, working);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_resolver_failover() {
+        let dead = lossy_resolver(usize::MAX).await;
+        let working = lossy_resolver(0).await;
+        let mut scanner = file_scanner(
+            "",
+            DnsConfig {
+                query_timeout: Duration::from_millis(50),
+                retries: 0,
+                max_seq: 4,
+                stop_on_gap: false,
+                concurrency: 4,
+                ..Default::default()
+            },
+        );
+        scanner.backend = Backend::resolvers(&[dead, working]).await.unwrap();
+
+        scanner.perform_dns_checks().await;
+        assert_eq!(scanner.backend.active().unwrap().addr, working);
+    }
+
+    /// A resolver answering `qtype` queries for names starting `host` with one
+    /// record carrying `rdata`, and every other query with no records.
+    async fn answering_resolver(host: &'static str, qtype: u8, rdata: Vec<u8>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            while let Ok((len, src)) = socket.recv_from(&mut buf).await {
+                // the question's name is a run of labels ending in a zero
+                let mut end = 12;
+                while buf[end] != 0 {
+                    end += usize::from(buf[end]) + 1;
+                }
+                let question = &buf[12..end + 5];
+                let asked = question[question.len() - 4..question.len() - 2] == [0, qtype];
+                let answer = asked && buf[13..len].starts_with(host.as_bytes());
+
+                let mut response = vec![buf[0], buf[1], 0x81, 0x80, 0, 1, 0, 0, 0, 0, 0, 0];
+                response[7] = u8::from(answer);
+                response.extend_from_slice(question);
+                if answer {
+                    // a pointer back to the question's name
+                    response.extend_from_slice(&[0xc0, 12, 0, qtype, 0, 1, 0, 0, 0, 60]);
+                    response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+                    response.extend_from_slice(&rdata);
+                }
+                let _ = socket.send_to(&response, src).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_ipv6_only_names_become_nodes() {
+        let ip: Ipv6Addr = "fd00::1".parse().unwrap();
+        let summaries = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&summaries);
+        let mut scanner = file_scanner(
+            "",
+            DnsConfig {
+                query_aaaa: true,
+                on_scan_complete: Some(Arc::new(move |_, summary| {
+                    seen.lock().unwrap().push(summary)
+                })),
+                ..Default::default()
+            },
+        );
+        let resolver = answering_resolver("live-uk-lon-1", 28, ip.octets().to_vec()).await;
+        scanner.backend = Backend::resolvers(&[resolver]).await.unwrap();
+        scanner.perform_dns_checks().await;
+
+        let v6 = scanner.nodes.ipv6_only();
+        assert_eq!(v6.len(), 1);
+        assert_eq!(v6[0].ip(), ip);
+        assert_eq!((v6[0].tag(), v6[0].seq()), ("uk-lon", 1));
+        assert_eq!(v6[0].domain(), "wavey.io");
+        assert!(scanner.nodes.is_empty());
+
+        let summaries = summaries.lock().unwrap();
+        assert_eq!(summaries[0].ipv6_only, 1);
+        // A and AAAA for -1, then both for -2, the gap
+        assert_eq!(summaries[0].queries, 4);
+    }
+
+    #[tokio::test]
+    async fn test_unconfirmed_name_claims_nothing() {
+        let ip = Ipv4Addr::new(10, 0, 0, 5);
+        let records = "live-uk-lon-1.wavey.io 10.0.0.5\n\
+                       live-uk-lon-2.wavey.io 10.0.0.5\n";
+        let mut scanner = file_scanner(records, DnsConfig::default());
+        // the confirming resolver only vouches for the second name
+        let confirm_with = answering_resolver("live-uk-lon-2", 1, ip.octets().to_vec()).await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(confirm_with).await.unwrap();
+        scanner.confirmer = Some((confirm_with, socket));
+        scanner.perform_dns_checks().await;
+
+        let node = scanner.nodes.get(&ip).unwrap();
+        assert_eq!(node.seq(), Some(2));
+    }
+
+    async fn timed_scan(resolver: SocketAddr, concurrency: usize) -> Duration {
+        let mut scanner = file_scanner(
+            "",
+            DnsConfig {
+                max_seq: 8,
+                stop_on_gap: false,
+                concurrency,
+                ..Default::default()
+            },
+        );
+        scanner.backend = Backend::resolvers(&[resolver]).await.unwrap();
+
+        let started = Instant::now();
+        scanner.perform_dns_checks().await;
+        started.elapsed()
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_probing_is_faster() {
+        let resolver = slow_resolver(Duration::from_millis(100)).await;
+
+        let sequential = timed_scan(resolver, 1).await;
+        let concurrent = timed_scan(resolver, 8).await;
+        assert!(sequential >= Duration::from_millis(800));
+        assert!(
+            concurrent < sequential / 4,
+            "concurrent {:?} vs sequential {:?}",
+            concurrent,
+            sequential
+        );
+    }
+
+    #[test]
+    fn test_bind_addr_matches_resolver_family() {
+        let v4: SocketAddr = "8.8.8.8:53".parse().unwrap();
+        let v6: SocketAddr = "[2001:4860:4860::8888]:53".parse().unwrap();
+        assert_eq!(bind_addr_for(&v4), "0.0.0.0:0".parse().unwrap());
+        assert_eq!(bind_addr_for(&v6), "[::]:0".parse().unwrap());
+    }
+
+    #[test]
+    fn test_next_check_follows_ttl() {
+        let config = DnsConfig {
+            check_interval: Duration::from_secs(3600),
+            min_check_interval: Duration::from_secs(30),
+            ..Default::default()
+        };
+        let secs = |s| Some(Duration::from_secs(s));
+
+        assert_eq!(next_check_in(&config, secs(300)), Duration::from_secs(300));
+        // pathological TTLs are held to the bounds
+        assert_eq!(next_check_in(&config, secs(0)), Duration::from_secs(30));
+        assert_eq!(
+            next_check_in(&config, secs(86400)),
+            Duration::from_secs(3600)
+        );
+        // nothing to go on, e.g. a hosts file
+        assert_eq!(next_check_in(&config, None), Duration::from_secs(3600));
+
+        let mut scan = Scan {
+            before: HashSet::new(),
+            resolved: HashSet::new(),
+            claimed: HashMap::new(),
+            summary: ScanSummary::default(),
+            min_ttl: None,
+        };
+        scan.observe_ttl(secs(600));
+        scan.observe_ttl(None);
+        scan.observe_ttl(secs(120));
+        scan.observe_ttl(secs(900));
+        assert_eq!(scan.min_ttl, secs(120));
+    }
+
+    /// A response to an A query for `name` carrying `answers`, each an owner
+    /// name, a TTL in seconds and either an alias or an address.
+    fn response(name: &str, answers: &[(&str, u32, Result<&str, Ipv4Addr>)]) -> Message {
+        fn encode_name(buf: &mut Vec<u8>, name: &str) {
+            for label in name.trim_end_matches('.').split('.') {
+                buf.push(label.len() as u8);
+                buf.extend_from_slice(label.as_bytes());
+            }
+            buf.push(0);
+        }
+
+        let mut buf = vec![0, 1, 0x81, 0x80, 0, 1, 0, answers.len() as u8, 0, 0, 0, 0];
+        encode_name(&mut buf, name);
+        buf.extend_from_slice(&[0, 1, 0, 1]);
+        for (owner, ttl, data) in answers {
+            encode_name(&mut buf, owner);
+            let rtype = if data.is_ok() { 5 } else { 1 };
+            buf.extend_from_slice(&[0, rtype, 0, 1]);
+            buf.extend_from_slice(&ttl.to_be_bytes());
+            let mut rdata = Vec::new();
+            match data {
+                Ok(target) => encode_name(&mut rdata, target),
+                Err(ip) => rdata.extend_from_slice(&ip.octets()),
+            }
+            buf.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            buf.extend_from_slice(&rdata);
+        }
+        Message::from_slice(&buf).unwrap()
+    }
+
+    #[test]
+    fn test_cname_chain() {
+        let name = "live-uk-lon-1.wavey.io";
+        let ip = Ipv4Addr::new(10, 0, 0, 7);
+        let stray = Ipv4Addr::new(10, 0, 0, 99);
+
+        // an A record for an unrelated name comes first and must be skipped
+        let answer = response(
+            name,
+            &[
+                ("other.wavey.io", 60, Err(stray)),
+                (name, 300, Ok("lb.wavey.io")),
+                ("lb.wavey.io", 120, Ok("node-7.wavey.io")),
+                ("node-7.wavey.io", 60, Err(ip)),
+            ],
+        );
+        assert_eq!(
+            cname_chain(&answer, name),
+            vec![name, "lb.wavey.io", "node-7.wavey.io"]
+        );
+        assert_eq!(first_a_record(&answer, name, false), Some(ip));
+        assert_eq!(answer_ttl(&answer), Some(Duration::from_secs(60)));
+
+        // a chain whose target wasn't glued resolves to nothing here, leaving
+        // resolve_a to query the target
+        let answer = response(name, &[(name, 300, Ok("lb.wavey.io"))]);
+        assert_eq!(first_a_record(&answer, name, false), None);
+        assert_eq!(cname_chain(&answer, name).last().unwrap(), "lb.wavey.io");
+
+        // a loop ends the chain rather than spinning
+        let answer = response(
+            name,
+            &[(name, 60, Ok("lb.wavey.io")), ("lb.wavey.io", 60, Ok(name))],
+        );
+        assert_eq!(cname_chain(&answer, name).len(), 2);
+        assert_eq!(first_a_record(&answer, name, false), None);
+    }
 }
@@ -0,0 +1,58 @@
+use std::fmt;
+use std::io;
+use std::net::Ipv4Addr;
+
+#[derive(Debug)]
+pub enum DiscoveryError {
+    /// A background discovery task panicked or was cancelled.
+    TaskFailed(String),
+    /// Settings that can never work together.
+    InvalidConfig(String),
+    /// Another host is already announcing this address.
+    DuplicateAddress(Ipv4Addr),
+    /// The discovery socket couldn't be bound or set up, e.g. the port is in
+    /// use or broadcast isn't permitted.
+    Bind(io::Error),
+    /// A configured interface doesn't exist on this host.
+    InvalidInterface(String),
+    /// A socket for talking to a DNS resolver couldn't be set up.
+    Resolver(io::Error),
+    /// None of our addresses are private, or in the networks we were told to
+    /// use, and no address was configured to use instead.
+    NoPrivateIp,
+    /// Nothing came back from the startup broadcast probe sent to this
+    /// address, so announcements are probably not being delivered.
+    ProbeFailed(String),
+}
+
+impl fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscoveryError::TaskFailed(reason) => write!(f, "discovery task failed: {}", reason),
+            DiscoveryError::InvalidConfig(reason) => {
+                write!(f, "invalid discovery configuration: {}", reason)
+            }
+            DiscoveryError::DuplicateAddress(ip) => {
+                write!(f, "another host is already announcing {}", ip)
+            }
+            DiscoveryError::Bind(e) => write!(f, "failed to set up discovery socket: {}", e),
+            DiscoveryError::InvalidInterface(name) => write!(f, "no such interface: {}", name),
+            DiscoveryError::Resolver(e) => write!(f, "failed to reach DNS resolver: {}", e),
+            DiscoveryError::NoPrivateIp => write!(f, "no private address to discover from"),
+            DiscoveryError::ProbeFailed(target) => write!(
+                f,
+                "no reply to the startup probe sent to {}; broadcast may be filtered",
+                target
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DiscoveryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DiscoveryError::Bind(e) | DiscoveryError::Resolver(e) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! Probing discovered nodes, so that ones that resolve or announce but don't
+//! answer can be skipped; see [`Node::is_healthy`](crate::Node::is_healthy)
+//! and [`Nodes::all_healthy`].
+
+use crate::Nodes;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout, Duration};
+use tracing::{debug, info, warn};
+
+/// How a node is probed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthProtocol {
+    /// A TCP connect; accepted is healthy.
+    Tcp,
+    /// An empty datagram; any reply is healthy.
+    Udp,
+}
+
+/// Probe settings for [`DnsConfig`](crate::dns::DnsConfig) and
+/// [`VlanConfig`](crate::vlan::VlanConfig).
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    pub protocol: HealthProtocol,
+    pub port: u16,
+    /// Time between rounds of probes.
+    pub interval: Duration,
+    /// How long each probe waits for an answer.
+    pub timeout: Duration,
+    /// Failures in a row before a node is marked unhealthy. One success
+    /// marks it healthy again.
+    pub unhealthy_after: u32,
+    /// Failures in a row before a node is removed outright. `None` only ever
+    /// marks it unhealthy, leaving removal to the reaper.
+    pub remove_after: Option<u32>,
+}
+
+impl HealthCheck {
+    /// TCP connects to `port` every 10 seconds, marking a node unhealthy
+    /// after three failures in a row.
+    pub fn tcp(port: u16) -> Self {
+        HealthCheck {
+            protocol: HealthProtocol::Tcp,
+            port,
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(1),
+            unhealthy_after: 3,
+            remove_after: None,
+        }
+    }
+
+    /// As [`tcp`](Self::tcp), with UDP probes.
+    pub fn udp(port: u16) -> Self {
+        HealthCheck {
+            protocol: HealthProtocol::Udp,
+            ..Self::tcp(port)
+        }
+    }
+}
+
+/// Probes every peer in `nodes` once per `check.interval` until `shutdown`
+/// fires.
+pub(crate) async fn run_health_checks(
+    nodes: Arc<Nodes>,
+    check: HealthCheck,
+    mut shutdown: watch::Receiver<()>,
+) {
+    let mut failures = HashMap::new();
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => {
+                info!("Shutdown signal received, stopping health check task");
+                break;
+            }
+            _ = sleep(check.interval) => check_all(&nodes, &check, &mut failures).await,
+        }
+    }
+}
+
+/// One round: probes every peer concurrently and updates its health.
+/// `failures` counts each node's failures in a row between rounds.
+async fn check_all(nodes: &Nodes, check: &HealthCheck, failures: &mut HashMap<Ipv4Addr, u32>) {
+    let mut probes = JoinSet::new();
+    for node in nodes.all().into_iter().filter(|node| !node.is_self()) {
+        let (ip, addr) = (node.ip(), node.addr(check.port));
+        let (protocol, wait) = (check.protocol, check.timeout);
+        probes.spawn(async move { (ip, probe(protocol, addr, wait).await) });
+    }
+
+    let mut probed = Vec::new();
+    while let Some(result) = probes.join_next().await {
+        let Ok((ip, healthy)) = result else {
+            continue;
+        };
+        probed.push(ip);
+        if healthy {
+            failures.remove(&ip);
+            nodes.set_healthy(&ip, true);
+            continue;
+        }
+        let failed = failures.entry(ip).or_insert(0);
+        *failed += 1;
+        debug!("Health probe of {} failed ({} in a row)", ip, failed);
+        if check.remove_after.is_some_and(|limit| *failed >= limit) {
+            warn!("Removing {} after {} failed health probes", ip, failed);
+            nodes.remove(ip);
+            failures.remove(&ip);
+        } else if *failed >= check.unhealthy_after {
+            if nodes.get(&ip).is_some_and(|node| node.is_healthy()) {
+                warn!("Marking {} unhealthy after {} failed probes", ip, failed);
+            }
+            nodes.set_healthy(&ip, false);
+        }
+    }
+    // forget nodes that have gone
+    failures.retain(|ip, _| probed.contains(ip));
+}
+
+async fn probe(protocol: HealthProtocol, addr: SocketAddr, wait: Duration) -> bool {
+    match protocol {
+        HealthProtocol::Tcp => matches!(timeout(wait, TcpStream::connect(addr)).await, Ok(Ok(_))),
+        HealthProtocol::Udp => {
+            let exchange = async {
+                let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+                socket.connect(addr).await?;
+                socket.send(&[]).await?;
+                socket.recv(&mut [0; 512]).await
+            };
+            matches!(timeout(wait, exchange).await, Ok(Ok(_)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{NodeEvent, NodeField};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_unreachable_nodes_demoted_then_removed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let up = Ipv4Addr::new(127, 0, 0, 1);
+        // the listener is only on 127.0.0.1, so this is refused
+        let down = Ipv4Addr::new(127, 0, 0, 2);
+        let nodes = Nodes::new().with_loopback_own_ip(false);
+        nodes.add(up, None, None);
+        nodes.add(down, None, None);
+        let mut events = nodes.events();
+
+        let check = HealthCheck {
+            unhealthy_after: 2,
+            remove_after: Some(3),
+            ..HealthCheck::tcp(port)
+        };
+        let mut failures = HashMap::new();
+
+        check_all(&nodes, &check, &mut failures).await;
+        assert!(nodes.get(&down).unwrap().is_healthy());
+
+        check_all(&nodes, &check, &mut failures).await;
+        assert!(nodes.get(&up).unwrap().is_healthy());
+        assert!(!nodes.get(&down).unwrap().is_healthy());
+        assert_eq!(nodes.all_healthy().len(), 1);
+        assert!(matches!(
+            events.try_recv(),
+            Ok(NodeEvent::Updated { ip, changed_fields }) if ip == down && changed_fields == vec![NodeField::Health]
+        ));
+        // still unhealthy after being refreshed by discovery
+        nodes.add(down, None, None);
+        assert!(!nodes.get(&down).unwrap().is_healthy());
+
+        check_all(&nodes, &check, &mut failures).await;
+        assert!(!nodes.contains(&down));
+        assert!(nodes.contains(&up));
+        assert!(failures.is_empty());
+    }
+}
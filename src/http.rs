@@ -0,0 +1,190 @@
+//! A tiny HTTP status endpoint for embedding discovery in a service.
+//!
+//! - `GET /nodes`: the node table as a JSON array, `last_seen_ms` in Unix
+//!   milliseconds
+//! - `GET /status`: readiness and node count
+//! - `GET /healthz`: 200 once initial discovery has completed, 503 before
+
+use crate::{Node, Nodes};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{oneshot, watch};
+use tracing::{debug, info, warn};
+
+/// Serves the status endpoints on `addr` until `shutdown` fires. `up` is the
+/// session's readiness signal, e.g. `handle.up`.
+pub async fn serve(
+    addr: SocketAddr,
+    nodes: Arc<Nodes>,
+    up: oneshot::Receiver<()>,
+    mut shutdown: watch::Receiver<()>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("HTTP status server listening on {}", addr);
+
+    let ready = Arc::new(AtomicBool::new(false));
+    let ready_clone = Arc::clone(&ready);
+    tokio::spawn(async move {
+        if up.await.is_ok() {
+            ready_clone.store(true, Ordering::Relaxed);
+        }
+    });
+
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => {
+                info!("Shutdown signal received, stopping HTTP status server");
+                return Ok(());
+            }
+            result = listener.accept() => {
+                match result {
+                    Ok((stream, peer)) => {
+                        let nodes = Arc::clone(&nodes);
+                        let ready = Arc::clone(&ready);
+                        tokio::spawn(async move {
+                            if let Err(e) = handle(stream, &nodes, &ready).await {
+                                debug!("HTTP request from {} failed: {}", peer, e);
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        warn!("Failed to accept HTTP connection: {}", e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn handle(mut stream: TcpStream, nodes: &Nodes, ready: &AtomicBool) -> io::Result<()> {
+    let mut buf = [0; 1024];
+    let len = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let mut parts = request.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("");
+
+    let (status, body) = route(method, path, nodes, ready.load(Ordering::Relaxed));
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn route(method: &str, path: &str, nodes: &Nodes, ready: bool) -> (&'static str, String) {
+    match (method, path) {
+        ("GET", "/nodes") => ("200 OK", nodes_json(&nodes.all())),
+        ("GET", "/status") => (
+            "200 OK",
+            format!(r#"{{"ready":{},"nodes":{}}}"#, ready, nodes.len()),
+        ),
+        ("GET", "/healthz") if ready => ("200 OK", r#"{"ok":true}"#.to_string()),
+        ("GET", "/healthz") => ("503 Service Unavailable", r#"{"ok":false}"#.to_string()),
+        ("GET", _) => ("404 Not Found", r#"{"error":"not found"}"#.to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            r#"{"error":"method not allowed"}"#.to_string(),
+        ),
+    }
+}
+
+fn nodes_json(nodes: &[Node]) -> String {
+    let items: Vec<String> = nodes.iter().map(node_json).collect();
+    format!("[{}]", items.join(","))
+}
+
+fn node_json(node: &Node) -> String {
+    let addrs: Vec<String> = node.addrs().iter().map(|a| format!("\"{}\"", a)).collect();
+    let tag = node
+        .tag()
+        .map(|t| json_string(t))
+        .unwrap_or_else(|| "null".to_string());
+    let seq = node
+        .seq()
+        .map(|s| s.to_string())
+        .unwrap_or_else(|| "null".to_string());
+    let last_seen = node
+        .last_seen_wall()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    format!(
+        r#"{{"ip":"{}","addrs":[{}],"tag":{},"seq":{},"is_self":{},"last_seen_ms":{}}}"#,
+        node.ip(),
+        addrs.join(","),
+        tag,
+        seq,
+        node.is_self(),
+        last_seen
+    )
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_route() {
+        let nodes = Nodes::new();
+        nodes.add(
+            Ipv4Addr::new(10, 0, 0, 1),
+            Some("uk-\"lon\"".into()),
+            Some(3),
+        );
+
+        let last_seen = nodes.all()[0]
+            .last_seen_wall()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+
+        let (status, body) = route("GET", "/nodes", &nodes, true);
+        assert_eq!(status, "200 OK");
+        assert_eq!(
+            body,
+            format!(
+                r#"[{{"ip":"10.0.0.1","addrs":["10.0.0.1"],"tag":"uk-\"lon\"","seq":3,"is_self":false,"last_seen_ms":{}}}]"#,
+                last_seen
+            )
+        );
+
+        let (_, body) = route("GET", "/status", &nodes, false);
+        assert_eq!(body, r#"{"ready":false,"nodes":1}"#);
+
+        assert_eq!(
+            route("GET", "/healthz", &nodes, false).0,
+            "503 Service Unavailable"
+        );
+        assert_eq!(route("GET", "/healthz", &nodes, true).0, "200 OK");
+        assert_eq!(route("GET", "/nope", &nodes, true).0, "404 Not Found");
+        assert_eq!(
+            route("POST", "/nodes", &nodes, true).0,
+            "405 Method Not Allowed"
+        );
+    }
+}
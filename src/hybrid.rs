@@ -0,0 +1,160 @@
+use crate::dns::{self, DnsConfig, DnsHandle, DnsSource, Names};
+use crate::vlan::{self, VlanConfig, VlanHandle};
+use crate::{DiscoveryError, Nodes};
+use std::sync::Arc;
+
+/// Settings for both halves of a [`discover`] session.
+#[derive(Debug, Default)]
+pub struct HybridConfig {
+    pub dns: DnsConfig,
+    pub vlan: VlanConfig,
+}
+
+/// DNS and VLAN discovery running together over one node table.
+pub struct HybridHandle {
+    pub nodes: Arc<Nodes>,
+    pub dns: DnsHandle,
+    pub vlan: VlanHandle,
+}
+
+impl HybridHandle {
+    /// Stops both sessions.
+    pub fn shutdown(&self) {
+        let _ = self.dns.shutdown.send(());
+        let _ = self.vlan.shutdown.send(());
+    }
+
+    /// False once either session has a task that has exited.
+    pub fn is_alive(&self) -> bool {
+        self.dns.is_alive() && self.vlan.is_alive()
+    }
+
+    /// Waits for both sessions to exit, surfacing the first task panic as
+    /// [`DiscoveryError::TaskFailed`].
+    pub async fn join(&mut self) -> Result<(), DiscoveryError> {
+        let vlan = self.vlan.join().await;
+        let dns = self.dns.join().await;
+        vlan.and(dns)
+    }
+}
+
+/// Runs [`vlan::discover`] on `broadcast_port` and [`dns::discover`] for
+/// `{prefix}-{tag}-{seq}.{domain}` under each of `domains` side by side,
+/// both feeding one [`Nodes`], so peers on the LAN and remote peers appear
+/// in one map with one event stream.
+///
+/// Both sources add our own addresses before either starts looking, so we
+/// are recognised as ourselves whichever one reports us. DNS names nodes: a
+/// VLAN announcement only fills in a tag or seq the node doesn't have yet, so
+/// a node seen by both keeps its DNS name rather than flipping on every
+/// announcement. The table reaps with the longer of the two silence windows,
+/// and takes the VLAN confirmation threshold and the DNS tag limits. While
+/// DNS is degraded nothing is reaped, VLAN peers included. Nodes are health
+/// checked through `dns.health_check`, and with the `serde` feature the table
+/// is persisted through `dns.persist`; the VLAN equivalents are ignored.
+pub async fn discover(
+    interfaces: impl IntoIterator<Item = impl Into<String>>,
+    source: impl Into<DnsSource>,
+    domains: impl IntoIterator<Item = impl Into<String>>,
+    prefix: String,
+    tags: Vec<String>,
+    broadcast_port: u16,
+    config: HybridConfig,
+) -> Result<HybridHandle, DiscoveryError> {
+    let HybridConfig {
+        dns: dns_config,
+        vlan: vlan_config,
+    } = config;
+    let vlan_config = VlanConfig {
+        health_check: None,
+        #[cfg(feature = "serde")]
+        persist: None,
+        ..vlan_config
+    };
+    let nodes = dns::nodes_for(&dns_config);
+    let silence_window = nodes
+        .silence_window()
+        .max(vlan_config.broadcast_interval * vlan_config.max_silent_intervals);
+    let nodes = Arc::new(
+        nodes
+            .with_confirmation_threshold(vlan_config.confirmation_threshold.max(1))
+            .with_silence_window(silence_window),
+    );
+
+    // VLAN first: it registers our private addresses and has no first scan
+    // to run, so DNS's first scan already knows every address that is ours
+    let vlan = vlan::start(broadcast_port, vlan_config, Arc::clone(&nodes), true).await?;
+    let names = Names::Tags { prefix, tags };
+    let dns = match dns::start(
+        dns::collect(interfaces),
+        source.into(),
+        dns::collect(domains),
+        names,
+        dns_config,
+        Arc::clone(&nodes),
+    )
+    .await
+    {
+        Ok(dns) => dns,
+        Err(e) => {
+            let _ = vlan.shutdown.send(());
+            return Err(e);
+        }
+    };
+
+    Ok(HybridHandle { nodes, dns, vlan })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use tokio::fs;
+
+    #[tokio::test]
+    async fn test_shared_nodes_keep_dns_names() {
+        let path = std::env::temp_dir().join(format!("hybrid-hosts-{}", std::process::id()));
+        fs::write(&path, "disco-api-1.example.com 10.9.0.7\n")
+            .await
+            .unwrap();
+
+        let vlan = VlanConfig {
+            socket: Some(std::net::UdpSocket::bind("127.0.0.1:0").unwrap()),
+            probe_timeout: None,
+            announce_leaving: false,
+            own_ip: Some(Ipv4Addr::LOCALHOST),
+            ..Default::default()
+        };
+        let mut handle = discover(
+            Vec::<String>::new(),
+            DnsSource::File(path.clone()),
+            ["example.com"],
+            "disco".into(),
+            vec!["api".into()],
+            0,
+            HybridConfig {
+                vlan,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        assert!(Arc::ptr_eq(&handle.nodes, &handle.dns.nodes));
+        assert!(Arc::ptr_eq(&handle.nodes, &handle.vlan.nodes));
+        let ip = Ipv4Addr::new(10, 9, 0, 7);
+        let node = handle.nodes.get(&ip).unwrap();
+        assert_eq!(node.tag().map(String::as_str), Some("api"));
+        assert_eq!(node.seq(), Some(1));
+
+        // the longer DNS window wins
+        assert_eq!(
+            handle.nodes.silence_window(),
+            DnsConfig::default().check_interval * crate::MAX_SILENT_INTERVALS as u32
+        );
+
+        handle.shutdown();
+        handle.join().await.unwrap();
+        let _ = fs::remove_file(&path).await;
+    }
+}
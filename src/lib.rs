@@ -1,25 +1,79 @@
 pub mod dns;
+mod error;
+pub mod health;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod hybrid;
+pub mod net;
+#[cfg(feature = "serde")]
+pub mod persist;
 pub mod server;
+pub mod stats;
 pub mod vlan;
+mod wire;
 
-use std::collections::HashMap;
-use std::net::{Ipv4Addr, SocketAddr};
-use std::sync::{Arc, RwLock};
-use tokio::sync::broadcast;
-use tokio::time::{Duration, Instant};
+pub use error::DiscoveryError;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt::Write;
+use std::future::{poll_fn, Future};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::Poll;
+use std::time::SystemTime;
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout_at, Duration, Instant};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
 use tracing::{error, info, warn};
 
 const DNS_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
 const BROADCAST_INTERVAL: Duration = Duration::from_secs(5);
 const MAX_SILENT_INTERVALS: u64 = 10;
+const SILENCE_WINDOW: Duration =
+    Duration::from_secs(BROADCAST_INTERVAL.as_secs() * MAX_SILENT_INTERVALS);
+/// Default size of the [`Nodes`] broadcast channels.
+const CHANNEL_CAPACITY: usize = 16;
+/// Default number of events [`Nodes::history`] keeps.
+const HISTORY_CAPACITY: usize = 256;
 
+/// A known peer, or ourselves. With the `serde` feature it serializes as its
+/// `ip`, `tag` and `seq`; everything else is local to this process, and a
+/// deserialized node is stamped as just seen.
 #[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(from = "NodeRecord", into = "NodeRecord")
+)]
 pub struct Node {
     ip: Ipv4Addr,
+    addrs: Vec<Ipv4Addr>,
+    ipv6_addrs: Vec<Ipv6Addr>,
     tag: Option<String>,
     seq: Option<u32>,
     last_seen: Instant,
+    /// `last_seen` on the wall clock, for anything leaving the process.
+    last_seen_wall: SystemTime,
     is_self: bool,
+    /// Consumer-local state, kept across refreshes and never sent anywhere.
+    annotations: HashMap<String, String>,
+    /// Published by the node itself, e.g. in DNS TXT records.
+    metadata: HashMap<String, String>,
+    /// From an SRV record, when discovered that way.
+    port: Option<u16>,
+    priority: Option<u16>,
+    weight: Option<u16>,
+    /// The DNS domain the node was last resolved under.
+    domain: Option<String>,
+    /// Cleared once health probes have failed repeatedly.
+    healthy: bool,
 }
 
 impl Node {
@@ -29,6 +83,14 @@ impl Node {
     pub fn addr(&self, port: u16) -> SocketAddr {
         SocketAddr::new(std::net::IpAddr::V4(self.ip()), port)
     }
+    /// Every address the node announced, primary (`ip`) first.
+    pub fn addrs(&self) -> &[Ipv4Addr] {
+        &self.addrs
+    }
+    /// IPv6 addresses learnt from AAAA records, kept across refreshes.
+    pub fn ipv6_addrs(&self) -> &[Ipv6Addr] {
+        &self.ipv6_addrs
+    }
     pub fn tag(&self) -> Option<&String> {
         self.tag.as_ref()
     }
@@ -38,61 +100,2030 @@ impl Node {
     pub fn is_self(&self) -> bool {
         self.is_self
     }
+    /// False once [`health`](crate::health) probes have failed repeatedly,
+    /// until one succeeds again. Nodes that are never probed stay healthy.
+    pub fn is_healthy(&self) -> bool {
+        self.healthy
+    }
+    /// When the node was last announced or resolved.
+    pub fn last_seen(&self) -> Instant {
+        self.last_seen
+    }
+    /// Whether the node has gone unseen for longer than `max_age`. The reaper
+    /// applies the same test with the silence window.
+    pub fn is_stale(&self, max_age: Duration) -> bool {
+        self.last_seen.elapsed() > max_age
+    }
+    /// When the node was last seen, as wall-clock time. Unlike the monotonic
+    /// time used for reaping, this means the same thing to other processes.
+    pub fn last_seen_wall(&self) -> SystemTime {
+        self.last_seen_wall
+    }
+    /// A value set with [`Nodes::annotate`].
+    pub fn annotation(&self, key: &str) -> Option<&str> {
+        self.annotations.get(key).map(String::as_str)
+    }
+    /// The service port from the node's SRV record.
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+    /// The SRV priority; lower is preferred.
+    pub fn priority(&self) -> Option<u16> {
+        self.priority
+    }
+    /// The SRV weight or, failing that, a `weight` published in the node's
+    /// [`metadata`](Self::metadata); see [`Nodes::pick_weighted`].
+    pub fn weight(&self) -> Option<u16> {
+        self.weight
+            .or_else(|| self.metadata.get("weight")?.parse().ok())
+    }
+    /// The DNS domain the node was last resolved under, when discovered
+    /// through DNS.
+    pub fn domain(&self) -> Option<&str> {
+        self.domain.as_deref()
+    }
+    /// Key/value pairs the node publishes about itself, such as region or
+    /// version, kept across refreshes.
+    pub fn metadata(&self) -> &HashMap<String, String> {
+        &self.metadata
+    }
+}
+
+/// A peer DNS found only AAAA records for, so with no IPv4 address to key it
+/// in the table by; see [`Nodes::ipv6_only`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ipv6Node {
+    ip: Ipv6Addr,
+    /// Every address in the AAAA answer, `ip` first.
+    addrs: Vec<Ipv6Addr>,
+    tag: String,
+    seq: u32,
+    domain: String,
+    last_seen: Instant,
+}
+
+impl Ipv6Node {
+    pub fn ip(&self) -> Ipv6Addr {
+        self.ip
+    }
+    pub fn addr(&self, port: u16) -> SocketAddr {
+        SocketAddr::new(std::net::IpAddr::V6(self.ip), port)
+    }
+    /// Every address the name resolved to, primary (`ip`) first.
+    pub fn addrs(&self) -> &[Ipv6Addr] {
+        &self.addrs
+    }
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+    pub fn seq(&self) -> u32 {
+        self.seq
+    }
+    /// The DNS domain the node was last resolved under.
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+    /// When the node was last resolved.
+    pub fn last_seen(&self) -> Instant {
+        self.last_seen
+    }
 }
 
+/// Reaps `nodes` every `interval` until `shutdown` fires. Both discovery
+/// modes run this as a task of its own, so eviction doesn't depend on
+/// announcements going out or scans completing.
+pub(crate) async fn run_reaper(
+    nodes: Arc<Nodes>,
+    interval: Duration,
+    mut shutdown: watch::Receiver<()>,
+) {
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => {
+                info!("Shutdown signal received, stopping reaper task");
+                break;
+            }
+            _ = sleep(interval) => nodes.reap(),
+        }
+    }
+}
+
+/// The wire form of a [`Node`].
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct NodeRecord {
+    ip: Ipv4Addr,
+    tag: Option<String>,
+    seq: Option<u32>,
+}
+
+#[cfg(feature = "serde")]
+impl From<Node> for NodeRecord {
+    fn from(node: Node) -> Self {
+        NodeRecord {
+            ip: node.ip,
+            tag: node.tag,
+            seq: node.seq,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<NodeRecord> for Node {
+    fn from(record: NodeRecord) -> Self {
+        Node {
+            ip: record.ip,
+            addrs: vec![record.ip],
+            ipv6_addrs: Vec::new(),
+            tag: record.tag,
+            seq: record.seq,
+            last_seen: Instant::now(),
+            last_seen_wall: SystemTime::now(),
+            is_self: false,
+            annotations: HashMap::new(),
+            metadata: HashMap::new(),
+            port: None,
+            priority: None,
+            weight: None,
+            domain: None,
+            healthy: true,
+        }
+    }
+}
+
+/// The background tasks behind a discovery session.
+#[derive(Default)]
+pub(crate) struct Tasks(Vec<JoinHandle<()>>);
+
+impl Tasks {
+    pub(crate) fn spawn<F>(&mut self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.0.push(tokio::spawn(task));
+    }
+
+    /// True while every task is still running.
+    pub(crate) fn is_alive(&self) -> bool {
+        !self.0.is_empty() && self.0.iter().all(|task| !task.is_finished())
+    }
+
+    /// Makes the tasks spawned so far all-or-nothing: if one of them panics the
+    /// rest are aborted rather than left running half a session. Tasks that
+    /// exit normally, i.e. on shutdown, are left to finish in their own time.
+    /// The panic still surfaces from [`join`](Self::join).
+    pub(crate) fn supervise(&mut self) {
+        let mut tasks = std::mem::take(&mut self.0);
+        self.spawn(async move {
+            while !tasks.is_empty() {
+                let (index, result) = poll_fn(|cx| {
+                    for (index, task) in tasks.iter_mut().enumerate() {
+                        if let Poll::Ready(result) = Pin::new(task).poll(cx) {
+                            return Poll::Ready((index, result));
+                        }
+                    }
+                    Poll::Pending
+                })
+                .await;
+                tasks.swap_remove(index);
+
+                if let Err(e) = result {
+                    error!("Discovery task failed, stopping the session: {}", e);
+                    for task in &tasks {
+                        task.abort();
+                    }
+                    for task in tasks {
+                        let _ = task.await;
+                    }
+                    if let Ok(panic) = e.try_into_panic() {
+                        std::panic::resume_unwind(panic);
+                    }
+                    return;
+                }
+            }
+        });
+    }
+
+    /// Waits for every task to exit, reporting the first one that panicked.
+    pub(crate) async fn join(&mut self) -> Result<(), DiscoveryError> {
+        let mut result = Ok(());
+        for task in self.0.drain(..) {
+            if let Err(e) = task.await {
+                error!("Discovery task failed: {}", e);
+                if result.is_ok() {
+                    result = Err(DiscoveryError::TaskFailed(e.to_string()));
+                }
+            }
+        }
+        result
+    }
+}
+
+/// A change in cluster membership, delivered on [`Nodes::events`].
+#[derive(Debug, Clone)]
+pub enum NodeEvent {
+    Joined(Node),
+    /// Reaped after staying silent too long.
+    Left(Ipv4Addr),
+    /// Removed because it announced it was shutting down.
+    Leaving(Ipv4Addr),
+    /// Removed with [`Nodes::remove`].
+    Removed(Ipv4Addr),
+    /// Several nodes left in the same reap, e.g. a rack losing power. Sent
+    /// instead of one `Left` per node so consumers reconcile once.
+    BatchLeft(Vec<Ipv4Addr>),
+    /// A known node came back with different metadata, e.g. a new seq after
+    /// being redeployed.
+    Updated {
+        ip: Ipv4Addr,
+        changed_fields: Vec<NodeField>,
+    },
+}
+
+impl NodeEvent {
+    /// The addresses this event takes out of the table, however they went:
+    /// reaped, said goodbye or removed by hand. Empty for joins and updates.
+    pub fn removed(&self) -> &[Ipv4Addr] {
+        match self {
+            NodeEvent::Left(ip) | NodeEvent::Leaving(ip) | NodeEvent::Removed(ip) => {
+                std::slice::from_ref(ip)
+            }
+            NodeEvent::BatchLeft(ips) => ips,
+            NodeEvent::Joined(_) | NodeEvent::Updated { .. } => &[],
+        }
+    }
+}
+
+/// The parts of a [`Node`] an [`NodeEvent::Updated`] can report changing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeField {
+    Tag,
+    Seq,
+    Addrs,
+    Health,
+    Ipv6Addrs,
+    /// Published metadata, e.g. from DNS TXT records.
+    Metadata,
+    Domain,
+    /// The port from an SRV record.
+    Port,
+    /// The priority from an SRV record.
+    Priority,
+    /// The weight from an SRV record.
+    Weight,
+}
+
+/// Sets `slot` to `value`, returning `field` if that changed it.
+fn replace_changed<T: PartialEq>(slot: &mut T, value: T, field: NodeField) -> Vec<NodeField> {
+    if *slot == value {
+        return Vec::new();
+    }
+    *slot = value;
+    vec![field]
+}
+
+/// What [`Nodes::add`] did with a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddOutcome {
+    /// It wasn't known before; [`Nodes::rx`] and a
+    /// [`NodeEvent::Joined`] announce it.
+    Inserted,
+    /// Known, and its tag, seq, addresses or domain changed.
+    Updated,
+    /// Known and unchanged; only `last_seen` was refreshed.
+    Unchanged,
+    /// Not in the table: held back pending confirmation, or rejected over a
+    /// tag limit.
+    NotAdded,
+}
+
+/// Delivery health of one of the [`Nodes`] broadcast channels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelStats {
+    /// Values still waiting on the slowest receiver. Once this reaches
+    /// `capacity` that receiver starts missing values.
+    pub backlog: usize,
+    pub capacity: usize,
+    pub receivers: usize,
+}
+
+impl ChannelStats {
+    fn of<T>(tx: &broadcast::Sender<T>, capacity: usize) -> Self {
+        ChannelStats {
+            backlog: tx.len(),
+            capacity,
+            receivers: tx.receiver_count(),
+        }
+    }
+}
+
+/// What [`Nodes`] does with a node that would take its tag past the limit set
+/// with [`Nodes::with_tag_limit`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TagLimitPolicy {
+    /// Log a warning and leave the node out.
+    Reject,
+    /// Log a warning and add it anyway.
+    Warn,
+}
+
+/// Kept ordered by IP so [`Nodes::ordered`] needn't sort from scratch.
+type Shard = RwLock<BTreeMap<Ipv4Addr, Node>>;
+
 pub struct Nodes {
-    data: Arc<RwLock<HashMap<Ipv4Addr, Node>>>,
+    shards: Vec<Shard>,
+    own_ips: RwLock<HashSet<Ipv4Addr>>,
+    pins: RwLock<HashMap<Ipv4Addr, Instant>>,
+    confirmation_threshold: u32,
+    clear_missing_metadata: bool,
+    tag_limits: HashMap<String, usize>,
+    tag_limit_policy: TagLimitPolicy,
+    /// Sightings so far, and when the first was, of nodes not yet confirmed.
+    provisional: RwLock<HashMap<Ipv4Addr, (u32, Instant)>>,
+    reaping_suspended: AtomicBool,
+    silence_window: Duration,
+    channel_capacity: usize,
     tx: broadcast::Sender<Node>,
+    events: broadcast::Sender<NodeEvent>,
+    snapshot: watch::Sender<Vec<Node>>,
+    /// The latest events, oldest first, with when each happened.
+    history: Mutex<VecDeque<(Instant, NodeEvent)>>,
+    history_capacity: usize,
+    /// Calls to [`pick_round_robin`](Self::pick_round_robin) so far.
+    round_robin: AtomicUsize,
+    /// Drives [`pick_random`](Self::pick_random) and
+    /// [`pick_weighted`](Self::pick_weighted).
+    rng: Mutex<StdRng>,
+    ipv6_only: RwLock<BTreeMap<Ipv6Addr, Ipv6Node>>,
 }
 
 impl Nodes {
     pub fn new() -> Self {
-        let (tx, _) = broadcast::channel::<Node>(16);
+        Self::with_shards(1)
+    }
+
+    /// Splits the table across `shards` independently locked maps keyed by
+    /// IP, so that many discovery tasks writing to one `Nodes` under heavy
+    /// churn don't all contend on a single lock. Only worth it at scale.
+    pub fn with_shards(shards: usize) -> Self {
+        let (tx, _) = broadcast::channel::<Node>(CHANNEL_CAPACITY);
+        let (events, _) = broadcast::channel::<NodeEvent>(CHANNEL_CAPACITY);
+        let (snapshot, _) = watch::channel(Vec::new());
         Nodes {
-            data: Arc::new(RwLock::new(HashMap::new())),
+            shards: (0..shards.max(1))
+                .map(|_| RwLock::new(BTreeMap::new()))
+                .collect(),
+            own_ips: RwLock::new(HashSet::from([Ipv4Addr::LOCALHOST])),
+            pins: RwLock::new(HashMap::new()),
+            confirmation_threshold: 1,
+            clear_missing_metadata: false,
+            tag_limits: HashMap::new(),
+            tag_limit_policy: TagLimitPolicy::Warn,
+            provisional: RwLock::new(HashMap::new()),
+            reaping_suspended: AtomicBool::new(false),
+            silence_window: SILENCE_WINDOW,
+            channel_capacity: CHANNEL_CAPACITY,
             tx,
+            events,
+            snapshot,
+            history: Mutex::new(VecDeque::new()),
+            history_capacity: HISTORY_CAPACITY,
+            round_robin: AtomicUsize::new(0),
+            rng: Mutex::new(StdRng::from_entropy()),
+            ipv6_only: RwLock::new(BTreeMap::new()),
         }
     }
 
+    /// Sizes the [`rx`](Self::rx) and [`events`](Self::events) channels, 16 by
+    /// default. Each holds that many values for its slowest receiver; once
+    /// that receiver is a full channel behind, the oldest values are
+    /// overwritten and its next `recv` returns `Lagged` with the number it
+    /// missed. Raise this for many subscribers or bursts of churn.
+    pub fn with_channel_capacity(mut self, capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        self.channel_capacity = capacity;
+        self.tx = broadcast::channel(capacity).0;
+        self.events = broadcast::channel(capacity).0;
+        self
+    }
+
+    /// Keeps the last `capacity` events for [`history`](Self::history), 256 by
+    /// default. `0` keeps none.
+    pub fn with_history_capacity(mut self, capacity: usize) -> Self {
+        self.history_capacity = capacity;
+        let history = self.history.get_mut().unwrap();
+        let excess = history.len().saturating_sub(capacity);
+        history.drain(..excess);
+        self
+    }
+
+    /// Registers our own addresses up front; see
+    /// [`add_own_ip`](Self::add_own_ip).
+    pub fn with_own_ips(self, ips: impl IntoIterator<Item = Ipv4Addr>) -> Self {
+        self.own_ips.write().unwrap().extend(ips);
+        self
+    }
+
+    /// Whether 127.0.0.1 counts as one of our own addresses, as it does by
+    /// default. Turn off to track a node on loopback, e.g. in tests.
+    pub fn with_loopback_own_ip(self, include: bool) -> Self {
+        let mut own_ips = self.own_ips.write().unwrap();
+        if include {
+            own_ips.insert(Ipv4Addr::LOCALHOST);
+        } else {
+            own_ips.remove(&Ipv4Addr::LOCALHOST);
+        }
+        drop(own_ips);
+        self
+    }
+
+    /// Seeds the choices of [`pick_random`](Self::pick_random) and
+    /// [`pick_weighted`](Self::pick_weighted), so a run can be reproduced in
+    /// tests. Seeded from entropy otherwise.
+    pub fn with_rng_seed(mut self, seed: u64) -> Self {
+        *self.rng.get_mut().unwrap() = StdRng::seed_from_u64(seed);
+        self
+    }
+
+    /// Holds new nodes back until they've been seen `threshold` times within
+    /// one silence window, so a single stray packet doesn't produce a join.
+    /// Nodes that never get there are dropped without any event.
+    pub fn with_confirmation_threshold(mut self, threshold: u32) -> Self {
+        self.confirmation_threshold = threshold.max(1);
+        self
+    }
+
+    /// How long a node may stay silent before it is reaped, and the window
+    /// within which confirmation sightings must fall. Defaults to ten
+    /// five-second broadcast intervals, which suits VLAN discovery; DNS
+    /// discovery uses ten of its check intervals instead, since it only
+    /// refreshes a node once per check.
+    pub fn with_silence_window(mut self, window: Duration) -> Self {
+        self.silence_window = window;
+        self
+    }
+
+    pub fn silence_window(&self) -> Duration {
+        self.silence_window
+    }
+
+    fn shard_index(&self, ip: &Ipv4Addr) -> usize {
+        u32::from(*ip) as usize % self.shards.len()
+    }
+
+    fn shard(&self, ip: &Ipv4Addr) -> &Shard {
+        &self.shards[self.shard_index(ip)]
+    }
+
+    /// Marks `ip` as one of this host's own addresses. Every discovery backend
+    /// feeding this table registers its addresses here, so self-filtering has
+    /// one source of truth. Loopback is included unless turned off with
+    /// [`with_loopback_own_ip`](Self::with_loopback_own_ip).
+    pub fn add_own_ip(&self, ip: Ipv4Addr) {
+        self.own_ips.write().unwrap().insert(ip);
+    }
+
+    pub fn is_own_ip(&self, ip: &Ipv4Addr) -> bool {
+        self.own_ips.read().unwrap().contains(ip)
+    }
+
+    pub fn own_ips(&self) -> HashSet<Ipv4Addr> {
+        self.own_ips.read().unwrap().clone()
+    }
+
+    /// New nodes only, kept for existing consumers. Subscribe to
+    /// [`events`](Self::events) to hear about removals too.
     pub fn rx(&self) -> broadcast::Receiver<Node> {
         self.tx.subscribe()
     }
 
-    pub fn test(&self, ip: &Ipv4Addr) -> bool {
-        let lock = self.data.read().unwrap();
+    /// Joins and departures, as opposed to [`rx`](Self::rx) which only
+    /// carries new nodes.
+    pub fn events(&self) -> broadcast::Receiver<NodeEvent> {
+        self.events.subscribe()
+    }
+
+    /// [`events`](Self::events) as a [`Stream`], for use with `StreamExt`.
+    /// Falling a channel behind is logged with the number of events skipped
+    /// and the stream carries on from the oldest event still held; it ends
+    /// once this `Nodes` is dropped.
+    pub fn event_stream(&self) -> impl Stream<Item = NodeEvent> {
+        BroadcastStream::new(self.events()).filter_map(|event| match event {
+            Ok(event) => Some(event),
+            Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                warn!("Event stream lagged, skipped {} events", skipped);
+                None
+            }
+        })
+    }
+
+    /// The whole table in IP order, republished every time it is reaped, so
+    /// a subscriber always holds the latest state without replaying events.
+    /// Starts out with the table as it is now.
+    ///
+    /// Each publish clones every node, and the latest copy stays alive until
+    /// the last receiver drops; nothing is cloned while nobody subscribes.
+    pub fn snapshots(&self) -> watch::Receiver<Vec<Node>> {
+        self.snapshot.send_replace(self.ordered());
+        self.snapshot.subscribe()
+    }
+
+    /// The last events, oldest first, each with when it happened, e.g. to
+    /// tell whether a node flapped in the last hour after it has been reaped.
+    /// Holds as many as [`with_history_capacity`](Self::with_history_capacity)
+    /// allows, whether or not anyone subscribed to [`events`](Self::events).
+    pub fn history(&self) -> Vec<(Instant, NodeEvent)> {
+        self.history.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn rx_stats(&self) -> ChannelStats {
+        ChannelStats::of(&self.tx, self.channel_capacity)
+    }
+
+    pub fn events_stats(&self) -> ChannelStats {
+        ChannelStats::of(&self.events, self.channel_capacity)
+    }
+
+    pub fn contains(&self, ip: &Ipv4Addr) -> bool {
+        let lock = self.shard(ip).read().unwrap();
         lock.contains_key(ip)
     }
 
-    pub fn add(&self, ip: Ipv4Addr, tag: Option<String>, seq: Option<u32>, is_self: bool) {
-        let node = Node {
+    /// A snapshot of the node at `ip`, if known.
+    pub fn get(&self, ip: &Ipv4Addr) -> Option<Node> {
+        let lock = self.shard(ip).read().unwrap();
+        lock.get(ip).cloned()
+    }
+
+    #[deprecated(note = "use `contains`")]
+    pub fn test(&self, ip: &Ipv4Addr) -> bool {
+        self.contains(ip)
+    }
+
+    /// Number of known nodes, ourselves included.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.read().unwrap().len()).sum()
+    }
+
+    /// Waits until at least `count` nodes are known, ourselves included.
+    /// Returns false if `timeout` passes first.
+    pub async fn wait_for(&self, count: usize, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        // subscribe before looking, so a join in between isn't missed
+        let mut events = self.events.subscribe();
+        loop {
+            if self.len() >= count {
+                return true;
+            }
+            // any event, or falling behind, is just a cue to count again
+            match timeout_at(deadline, events.recv()).await {
+                Ok(Ok(_)) | Ok(Err(broadcast::error::RecvError::Lagged(_))) => {}
+                Ok(Err(broadcast::error::RecvError::Closed)) | Err(_) => {
+                    return self.len() >= count;
+                }
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|s| s.read().unwrap().is_empty())
+    }
+
+    /// Attaches a consumer-local key/value to a known node. Annotations survive
+    /// the node being refreshed by discovery and go away when it is removed.
+    /// Returns whether the node was known.
+    pub fn annotate(
+        &self,
+        ip: &Ipv4Addr,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> bool {
+        self.update_known(ip, |node| {
+            node.annotations.insert(key.into(), value.into());
+            // annotations are local bookkeeping, not news about the node
+            Vec::new()
+        })
+    }
+
+    /// Records the IPv6 addresses of a known node. Returns whether the node was
+    /// known.
+    pub fn set_ipv6_addrs(&self, ip: &Ipv4Addr, ipv6_addrs: Vec<Ipv6Addr>) -> bool {
+        self.update_known(ip, |node| {
+            replace_changed(&mut node.ipv6_addrs, ipv6_addrs, NodeField::Ipv6Addrs)
+        })
+    }
+
+    /// Records the published metadata of a known node. Returns whether the
+    /// node was known.
+    pub fn set_metadata(&self, ip: &Ipv4Addr, metadata: HashMap<String, String>) -> bool {
+        self.update_known(ip, |node| {
+            replace_changed(&mut node.metadata, metadata, NodeField::Metadata)
+        })
+    }
+
+    /// Records the port, priority and weight from a known node's SRV record,
+    /// all under one lock. Returns whether the node was known.
+    pub fn set_srv(&self, ip: &Ipv4Addr, port: u16, priority: u16, weight: u16) -> bool {
+        self.update_known(ip, |node| {
+            let mut changed_fields = replace_changed(&mut node.port, Some(port), NodeField::Port);
+            changed_fields.extend(replace_changed(
+                &mut node.priority,
+                Some(priority),
+                NodeField::Priority,
+            ));
+            changed_fields.extend(replace_changed(
+                &mut node.weight,
+                Some(weight),
+                NodeField::Weight,
+            ));
+            changed_fields
+        })
+    }
+
+    /// Marks a known node healthy or not. Returns whether the node was known.
+    pub fn set_healthy(&self, ip: &Ipv4Addr, healthy: bool) -> bool {
+        self.update_known(ip, |node| {
+            replace_changed(&mut node.healthy, healthy, NodeField::Health)
+        })
+    }
+
+    /// Applies `update` to a known node under the write lock, then announces
+    /// the fields it reports changing with a [`NodeEvent::Updated`], if any.
+    /// Returns whether the node was known.
+    fn update_known(
+        &self,
+        ip: &Ipv4Addr,
+        update: impl FnOnce(&mut Node) -> Vec<NodeField>,
+    ) -> bool {
+        let mut lock = self.shard(ip).write().unwrap();
+        let Some(node) = lock.get_mut(ip) else {
+            return false;
+        };
+        let changed_fields = update(node);
+        drop(lock);
+        if !changed_fields.is_empty() {
+            self.emit(NodeEvent::Updated {
+                ip: *ip,
+                changed_fields,
+            });
+        }
+        true
+    }
+
+    /// Drops a node straight away rather than waiting for it to be reaped,
+    /// e.g. once it is known to have left the cluster. Returns whether it was
+    /// known.
+    pub fn remove(&self, ip: Ipv4Addr) -> bool {
+        self.evict(&ip, NodeEvent::Removed(ip))
+    }
+
+    /// Drops a node that announced its departure. Returns whether it was known.
+    pub(crate) fn depart(&self, ip: &Ipv4Addr) -> bool {
+        self.evict(ip, NodeEvent::Leaving(*ip))
+    }
+
+    fn evict(&self, ip: &Ipv4Addr, event: NodeEvent) -> bool {
+        let removed = self.shard(ip).write().unwrap().remove(ip).is_some();
+        if removed {
+            self.pins.write().unwrap().remove(ip);
+            self.emit(event);
+            stats::node_count(self);
+        }
+        removed
+    }
+
+    /// Keeps `ip` from being reaped until `until`, however long it stays
+    /// silent. For peers known to be healthy but briefly unreachable.
+    pub fn pin(&self, ip: Ipv4Addr, until: Instant) {
+        self.pins.write().unwrap().insert(ip, until);
+    }
+
+    pub fn unpin(&self, ip: &Ipv4Addr) {
+        self.pins.write().unwrap().remove(ip);
+    }
+
+    /// [`contains`](Self::contains) for many addresses at once, under one read lock
+    /// per shard rather than one per address.
+    pub fn contains_all(&self, ips: &[Ipv4Addr]) -> Vec<bool> {
+        let locks: Vec<_> = self.shards.iter().map(|s| s.read().unwrap()).collect();
+        ips.iter()
+            .map(|ip| locks[self.shard_index(ip)].contains_key(ip))
+            .collect()
+    }
+
+    /// Inserts a node discovery hasn't confirmed, such as a seed peer. It is
+    /// stamped as just seen, so it survives a full silence window before
+    /// being reaped unless discovery refreshes it first. Neither the
+    /// confirmation threshold nor the tag limits apply.
+    pub fn insert_unchecked(
+        &self,
+        ip: Ipv4Addr,
+        tag: Option<String>,
+        seq: Option<u32>,
+    ) -> AddOutcome {
+        self.upsert(vec![ip], tag, seq, None, false)
+    }
+
+    /// Adds or refreshes a node. `last_seen` is always refreshed, and a known
+    /// node takes on any tag or seq given. It counts as ourselves if `ip` has
+    /// been registered with [`add_own_ip`](Self::add_own_ip).
+    pub fn add(&self, ip: Ipv4Addr, tag: Option<String>, seq: Option<u32>) -> AddOutcome {
+        self.add_multihomed(vec![ip], tag, seq)
+    }
+
+    /// Adds a node reachable on several addresses as one entry, keyed by the
+    /// first (primary) address in `addrs`.
+    pub fn add_multihomed(
+        &self,
+        addrs: Vec<Ipv4Addr>,
+        tag: Option<String>,
+        seq: Option<u32>,
+    ) -> AddOutcome {
+        self.upsert(addrs, tag, seq, None, true)
+    }
+
+    /// Adds a node DNS resolved under `domain`, so it is known by the time
+    /// [`NodeEvent::Joined`] and [`rx`](Self::rx) announce it.
+    pub(crate) fn add_resolved(
+        &self,
+        ip: Ipv4Addr,
+        tag: String,
+        seq: u32,
+        domain: String,
+    ) -> AddOutcome {
+        self.upsert(vec![ip], Some(tag), Some(seq), Some(domain), true)
+    }
+
+    /// Adds or refreshes a node; `checked` applies the tag limits and holds a
+    /// new node back until it is confirmed.
+    fn upsert(
+        &self,
+        addrs: Vec<Ipv4Addr>,
+        tag: Option<String>,
+        seq: Option<u32>,
+        domain: Option<String>,
+        checked: bool,
+    ) -> AddOutcome {
+        let Some(&ip) = addrs.first() else {
+            return AddOutcome::NotAdded;
+        };
+        let is_self = self.is_own_ip(&ip);
+        let mut node = Node {
             ip,
+            addrs,
+            ipv6_addrs: Vec::new(),
             last_seen: Instant::now(),
+            last_seen_wall: SystemTime::now(),
             tag,
             seq,
             is_self,
+            annotations: HashMap::new(),
+            metadata: HashMap::new(),
+            port: None,
+            priority: None,
+            weight: None,
+            domain,
+            healthy: true,
         };
 
-        let mut lock = self.data.write().unwrap();
-        // only notify if the ip was initially absent
-        if !lock.contains_key(&ip) {
-            let _ = self.tx.send(node.clone());
+        if let Some(limit) = self.at_tag_limit(&ip, &node.tag).filter(|_| checked) {
+            warn!(
+                "{} would be node {} tagged {:?}, above the expected {}; \
+                 check DNS or look for a rogue announcer",
+                ip,
+                limit + 1,
+                node.tag,
+                limit
+            );
+            if self.tag_limit_policy == TagLimitPolicy::Reject {
+                return AddOutcome::NotAdded;
+            }
         }
+
+        let mut lock = self.shard(&ip).write().unwrap();
+        let outcome = match lock.get_mut(&ip) {
+            None if checked && !self.confirm(&ip) => return AddOutcome::NotAdded,
+            // only notify if the ip was initially absent
+            None => {
+                self.send_lossy(&self.tx, node.clone(), "rx");
+                self.emit(NodeEvent::Joined(node.clone()));
+                AddOutcome::Inserted
+            }
+            Some(existing) => {
+                if conflicts(&existing.tag, &node.tag) || conflicts(&existing.seq, &node.seq) {
+                    warn!(
+                        "Conflicting metadata for {}: tag={:?} seq={:?} replaced by tag={:?} seq={:?}; \
+                         two names may resolve to the same address",
+                        ip, existing.tag, existing.seq, node.tag, node.seq
+                    );
+                }
+                if !self.clear_missing_metadata {
+                    if node.tag.is_none() {
+                        node.tag = existing.tag.clone();
+                    }
+                    if node.seq.is_none() {
+                        node.seq = existing.seq;
+                    }
+                }
+                node.annotations = std::mem::take(&mut existing.annotations);
+                node.ipv6_addrs = std::mem::take(&mut existing.ipv6_addrs);
+                node.metadata = std::mem::take(&mut existing.metadata);
+                node.port = existing.port;
+                node.priority = existing.priority;
+                node.weight = existing.weight;
+                if node.domain.is_none() {
+                    node.domain = existing.domain.take();
+                }
+                node.healthy = existing.healthy;
+
+                let mut changed_fields = Vec::new();
+                if node.tag != existing.tag {
+                    changed_fields.push(NodeField::Tag);
+                }
+                if node.seq != existing.seq {
+                    changed_fields.push(NodeField::Seq);
+                }
+                if node.addrs != existing.addrs {
+                    changed_fields.push(NodeField::Addrs);
+                }
+                if node.domain != existing.domain {
+                    changed_fields.push(NodeField::Domain);
+                }
+                if changed_fields.is_empty() {
+                    AddOutcome::Unchanged
+                } else {
+                    self.emit(NodeEvent::Updated { ip, changed_fields });
+                    AddOutcome::Updated
+                }
+            }
+        };
         // always overwrite to update last seen
         lock.insert(ip.clone(), node);
+        drop(lock);
+        if outcome == AddOutcome::Inserted {
+            stats::node_added();
+            stats::node_count(self);
+        }
+        outcome
+    }
+
+    /// By default a refresh that doesn't know a node's tag or seq (say, a VLAN
+    /// announcement for a node DNS already named) keeps the values learnt
+    /// earlier. Set this to have such refreshes clear them instead.
+    pub fn with_clear_missing_metadata(mut self, clear: bool) -> Self {
+        self.clear_missing_metadata = clear;
+        self
+    }
+
+    /// Expects at most `max` nodes tagged `tag`. More usually means a DNS
+    /// misconfiguration or a rogue announcer, so going over is logged and
+    /// handled per [`with_tag_limit_policy`](Self::with_tag_limit_policy).
+    pub fn with_tag_limit(mut self, tag: impl Into<String>, max: usize) -> Self {
+        self.tag_limits.insert(tag.into(), max);
+        self
+    }
+
+    pub fn with_tag_limit_policy(mut self, policy: TagLimitPolicy) -> Self {
+        self.tag_limit_policy = policy;
+        self
+    }
+
+    /// Whether `tag` already has its limit of nodes other than `ip`. Counted
+    /// before the caller takes its shard lock, so a burst of concurrent adds
+    /// can overshoot slightly.
+    fn at_tag_limit(&self, ip: &Ipv4Addr, tag: &Option<String>) -> Option<usize> {
+        let tag = tag.as_ref()?;
+        let limit = *self.tag_limits.get(tag)?;
+        let mut count = 0;
+        for shard in &self.shards {
+            let lock = shard.read().unwrap();
+            count += lock
+                .values()
+                .filter(|node| node.ip != *ip && node.tag.as_ref() == Some(tag))
+                .count();
+        }
+        (count >= limit).then_some(limit)
+    }
+
+    /// Counts a sighting of an unknown node, returning true once it has been
+    /// seen often enough to be reported.
+    fn confirm(&self, ip: &Ipv4Addr) -> bool {
+        if self.confirmation_threshold <= 1 {
+            return true;
+        }
+
+        let now = Instant::now();
+        let mut provisional = self.provisional.write().unwrap();
+        let (count, first_seen) = provisional.entry(*ip).or_insert((0, now));
+        if now.duration_since(*first_seen) > self.silence_window {
+            *count = 0;
+            *first_seen = now;
+        }
+        *count += 1;
+        if *count >= self.confirmation_threshold {
+            provisional.remove(ip);
+            true
+        } else {
+            false
+        }
     }
 
     pub fn all(&self) -> Vec<Node> {
-        let lock = self.data.read().unwrap();
-        lock.values().cloned().collect()
+        let mut all = Vec::new();
+        for shard in &self.shards {
+            let lock = shard.read().unwrap();
+            all.extend(lock.values().cloned());
+        }
+        all
+    }
+
+    /// Like [`all`](Self::all), but in IP order. Each shard is already
+    /// ordered, so with one shard this is a straight copy and with several
+    /// it's a merge of pre-sorted runs.
+    pub fn ordered(&self) -> Vec<Node> {
+        let mut ordered = self.all();
+        if self.shards.len() > 1 {
+            // stable sort picks up the per-shard runs
+            ordered.sort_by_key(|node| node.ip);
+        }
+        ordered
+    }
+
+    /// Like [`ordered`](Self::ordered), but most preferred SRV priority
+    /// first. Nodes without a priority come last.
+    pub fn by_priority(&self) -> Vec<Node> {
+        let mut nodes = self.ordered();
+        nodes.sort_by_key(|node| node.priority.unwrap_or(u16::MAX));
+        nodes
+    }
+
+    /// Nodes tagged `tag`, in IP order. Filtered under the read lock, so only
+    /// the matches are cloned.
+    pub fn all_with_tag(&self, tag: &str) -> Vec<Node> {
+        self.filtered(|node| node.tag.as_deref() == Some(tag))
+    }
+
+    /// Peers DNS found only AAAA records for, in IP order. They sit apart
+    /// from the IPv4-keyed table: no events announce them, and they are
+    /// reaped on the same silence window but never probed or picked.
+    pub fn ipv6_only(&self) -> Vec<Ipv6Node> {
+        self.ipv6_only.read().unwrap().values().cloned().collect()
+    }
+
+    /// Adds or refreshes an IPv6-only peer, keyed by the first of `addrs`.
+    /// Returns true if it wasn't known before.
+    pub(crate) fn add_ipv6_only(
+        &self,
+        addrs: Vec<Ipv6Addr>,
+        tag: String,
+        seq: u32,
+        domain: String,
+    ) -> bool {
+        let Some(&ip) = addrs.first() else {
+            return false;
+        };
+        let node = Ipv6Node {
+            ip,
+            addrs,
+            tag,
+            seq,
+            domain,
+            last_seen: Instant::now(),
+        };
+        self.ipv6_only.write().unwrap().insert(ip, node).is_none()
+    }
+
+    /// Nodes not marked unhealthy, in IP order, e.g. for a load balancer to
+    /// pick from.
+    pub fn all_healthy(&self) -> Vec<Node> {
+        self.filtered(|node| node.healthy)
+    }
+
+    /// A healthy peer chosen uniformly at random, or `None` if there are
+    /// none. Ourselves and unhealthy nodes are never picked, here or by the
+    /// other `pick_*` helpers.
+    pub fn pick_random(&self) -> Option<Node> {
+        let mut candidates = self.candidates();
+        if candidates.is_empty() {
+            return None;
+        }
+        let i = self.rng.lock().unwrap().gen_range(0..candidates.len());
+        Some(candidates.swap_remove(i))
+    }
+
+    /// The next healthy peer in IP order, wrapping around. While the set of
+    /// candidates stays the same every one of them is picked once per that
+    /// many calls, however the calls are spread across threads; a node
+    /// joining or leaving shifts the rotation, so one may then be picked
+    /// early or late once.
+    pub fn pick_round_robin(&self) -> Option<Node> {
+        let mut candidates = self.candidates();
+        if candidates.is_empty() {
+            return None;
+        }
+        let i = self.round_robin.fetch_add(1, Ordering::Relaxed) % candidates.len();
+        Some(candidates.swap_remove(i))
+    }
+
+    /// A healthy peer chosen at random in proportion to its
+    /// [`weight`](Node::weight). As in RFC 2782, weights only compete within
+    /// the most preferred [`priority`](Node::priority) on offer, so a backup
+    /// is only picked once no primary is healthy; peers without a priority
+    /// rank last, as in [`by_priority`](Self::by_priority). Peers without a
+    /// weight, or weighted 0, are never picked; `None` if no peer has a
+    /// positive weight.
+    pub fn pick_weighted(&self) -> Option<Node> {
+        let mut weighted: Vec<(u32, Node)> = self
+            .candidates()
+            .into_iter()
+            .filter_map(|node| Some((u32::from(node.weight()?), node)))
+            .filter(|(weight, _)| *weight > 0)
+            .collect();
+        let preferred = weighted
+            .iter()
+            .map(|(_, node)| node.priority.unwrap_or(u16::MAX))
+            .min()?;
+        weighted.retain(|(_, node)| node.priority.unwrap_or(u16::MAX) == preferred);
+        let total: u32 = weighted.iter().map(|(weight, _)| weight).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut point = self.rng.lock().unwrap().gen_range(0..total);
+        let i = weighted.iter().position(|(weight, _)| {
+            if point < *weight {
+                return true;
+            }
+            point -= weight;
+            false
+        })?;
+        Some(weighted.swap_remove(i).1)
+    }
+
+    fn candidates(&self) -> Vec<Node> {
+        self.filtered(|node| node.healthy && !node.is_self)
+    }
+
+    fn filtered(&self, keep: impl Fn(&Node) -> bool) -> Vec<Node> {
+        let mut matched = Vec::new();
+        for shard in &self.shards {
+            let lock = shard.read().unwrap();
+            matched.extend(lock.values().filter(|node| keep(node)).cloned());
+        }
+        if self.shards.len() > 1 {
+            matched.sort_by_key(|node| node.ip);
+        }
+        matched
+    }
+
+    /// Every tag at least one node carries.
+    pub fn tags(&self) -> HashSet<String> {
+        let mut tags = HashSet::new();
+        for shard in &self.shards {
+            let lock = shard.read().unwrap();
+            for tag in lock.values().filter_map(|node| node.tag.as_ref()) {
+                if !tags.contains(tag) {
+                    tags.insert(tag.clone());
+                }
+            }
+        }
+        tags
+    }
+
+    /// Nodes tagged `a` whose address doesn't also appear under tag `b`.
+    pub fn tag_diff(&self, a: &str, b: &str) -> Vec<Node> {
+        let (in_a, in_b) = self.split_tags(a, b);
+        in_a.into_iter()
+            .filter(|node| !in_b.iter().any(|other| other.ip == node.ip))
+            .collect()
+    }
+
+    /// Nodes under exactly one of tags `a` and `b`.
+    pub fn tag_symmetric_diff(&self, a: &str, b: &str) -> Vec<Node> {
+        let (in_a, in_b) = self.split_tags(a, b);
+        let only_a = in_a
+            .iter()
+            .filter(|node| !in_b.iter().any(|other| other.ip == node.ip));
+        let only_b = in_b
+            .iter()
+            .filter(|node| !in_a.iter().any(|other| other.ip == node.ip));
+        only_a.chain(only_b).cloned().collect()
+    }
+
+    fn split_tags(&self, a: &str, b: &str) -> (Vec<Node>, Vec<Node>) {
+        let mut in_a = Vec::new();
+        let mut in_b = Vec::new();
+        for shard in &self.shards {
+            let lock = shard.read().unwrap();
+            for node in lock.values() {
+                match node.tag.as_deref() {
+                    Some(tag) if tag == a => in_a.push(node.clone()),
+                    Some(tag) if tag == b => in_b.push(node.clone()),
+                    _ => {}
+                }
+            }
+        }
+        (in_a, in_b)
+    }
+
+    /// Renders the membership table as a Graphviz DOT graph, one cluster per
+    /// tag. Pipe the output to `dot -Tpng` to get a picture of the cluster.
+    pub fn to_dot(&self) -> String {
+        let mut nodes = self.all();
+        nodes.sort_by_key(|node| node.ip);
+
+        let mut groups: BTreeMap<Option<&String>, Vec<&Node>> = BTreeMap::new();
+        for node in &nodes {
+            groups.entry(node.tag()).or_default().push(node);
+        }
+
+        let mut out = String::from("graph discovery {\n");
+        for (i, (tag, members)) in groups.iter().enumerate() {
+            let label = tag.map(|t| t.as_str()).unwrap_or("untagged");
+            writeln!(out, "  subgraph cluster_{} {{", i).unwrap();
+            writeln!(out, "    label=\"{}\";", dot_escape(label)).unwrap();
+            for node in members {
+                let mut node_label = node.ip.to_string();
+                if let Some(seq) = node.seq {
+                    write!(node_label, "\\n#{}", seq).unwrap();
+                }
+                let style = if node.is_self { ", style=bold" } else { "" };
+                writeln!(
+                    out,
+                    "    \"{}\" [label=\"{}\"{}];",
+                    node.ip, node_label, style
+                )
+                .unwrap();
+            }
+            out.push_str("  }\n");
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// Stops silent nodes from being reaped until called again with `false`,
+    /// for when silence says more about our view of the cluster than about the
+    /// nodes themselves.
+    pub fn suspend_reaping(&self, suspended: bool) {
+        self.reaping_suspended.store(suspended, Ordering::Relaxed);
+    }
+
+    pub fn is_reaping_suspended(&self) -> bool {
+        self.reaping_suspended.load(Ordering::Relaxed)
+    }
+
+    /// Sends on a broadcast channel, complaining when the slowest receiver has
+    /// fallen far enough behind that it will soon start losing values.
+    fn send_lossy<T>(&self, tx: &broadcast::Sender<T>, value: T, channel: &str) {
+        let lag_warn = self.channel_capacity * 3 / 4;
+        if tx.send(value).is_ok() && tx.len() >= lag_warn.max(1) {
+            warn!(
+                "{} channel backlog at {}/{}; a receiver is falling behind and will miss events",
+                channel,
+                tx.len(),
+                self.channel_capacity
+            );
+        }
+    }
+
+    /// Sends `event` on [`events`](Self::events) and keeps it in the history.
+    fn emit(&self, event: NodeEvent) {
+        if self.history_capacity > 0 {
+            let mut history = self.history.lock().unwrap();
+            if history.len() == self.history_capacity {
+                history.pop_front();
+            }
+            history.push_back((Instant::now(), event.clone()));
+        }
+        self.send_lossy(&self.events, event, "events");
     }
 
     fn reap(&self) {
-        let mut nodes_map = self.data.write().unwrap();
+        self.reap_older_than(self.silence_window);
+    }
+
+    fn reap_older_than(&self, max_age: Duration) {
+        self.expire(max_age);
+        if self.snapshot.receiver_count() > 0 {
+            self.snapshot.send_replace(self.ordered());
+        }
+    }
+
+    fn expire(&self, max_age: Duration) {
+        if self.is_reaping_suspended() {
+            return;
+        }
+
         let current_time = Instant::now();
-        nodes_map.retain(|_, node| {
-            let node_last_seen_duration = current_time.duration_since(node.last_seen);
-            let silent_intervals_seconds = MAX_SILENT_INTERVALS * BROADCAST_INTERVAL.as_secs();
-            node_last_seen_duration.as_secs() <= silent_intervals_seconds
+        let mut pins = self.pins.write().unwrap();
+        pins.retain(|_, until| *until > current_time);
+
+        let mut left = Vec::new();
+        for shard in &self.shards {
+            let mut nodes_map = shard.write().unwrap();
+            nodes_map.retain(|ip, node| {
+                let keep =
+                    pins.contains_key(ip) || current_time.duration_since(node.last_seen) <= max_age;
+                if !keep {
+                    left.push(*ip);
+                }
+                keep
+            });
+        }
+
+        drop(pins);
+
+        self.ipv6_only
+            .write()
+            .unwrap()
+            .retain(|_, node| current_time.duration_since(node.last_seen) <= max_age);
+
+        self.provisional
+            .write()
+            .unwrap()
+            .retain(|_, (_, first_seen)| current_time.duration_since(*first_seen) <= max_age);
+
+        if !left.is_empty() {
+            stats::nodes_reaped(left.len());
+            stats::node_count(self);
+        }
+        match left.len() {
+            0 => {}
+            1 => {
+                self.emit(NodeEvent::Left(left[0]));
+            }
+            _ => {
+                self.emit(NodeEvent::BatchLeft(left));
+            }
+        }
+    }
+}
+
+/// Whether two known values for the same field disagree.
+fn conflicts<T: PartialEq>(existing: &Option<T>, incoming: &Option<T>) -> bool {
+    matches!((existing, incoming), (Some(a), Some(b)) if a != b)
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nodes_to_dot() {
+        let nodes = Nodes::new();
+        nodes.add(Ipv4Addr::new(10, 0, 0, 1), Some("uk-lon".into()), Some(1));
+        nodes.add_own_ip(Ipv4Addr::new(10, 0, 0, 2));
+        nodes.add(Ipv4Addr::new(10, 0, 0, 2), None, None);
+
+        let dot = nodes.to_dot();
+        assert!(dot.starts_with("graph discovery {"));
+        assert!(dot.contains("label=\"uk-lon\";"));
+        assert!(dot.contains("label=\"untagged\";"));
+        assert!(dot.contains("\"10.0.0.1\" [label=\"10.0.0.1\\n#1\"];"));
+        assert!(dot.contains("\"10.0.0.2\" [label=\"10.0.0.2\", style=bold];"));
+    }
+
+    #[test]
+    fn test_sharded_nodes() {
+        let nodes = Nodes::with_shards(4);
+        assert!(nodes.is_empty());
+        for i in 1..=10 {
+            nodes.add(Ipv4Addr::new(10, 0, 0, i), None, None);
+        }
+        assert_eq!(nodes.all().len(), 10);
+        assert_eq!(nodes.len(), 10);
+        assert!(!nodes.is_empty());
+        assert!(nodes.contains(&Ipv4Addr::new(10, 0, 0, 7)));
+        assert!(!nodes.contains(&Ipv4Addr::new(10, 0, 0, 11)));
+    }
+
+    #[test]
+    fn test_get() {
+        let nodes = Nodes::with_shards(4);
+        nodes.add(Ipv4Addr::new(10, 0, 0, 1), Some("uk-lon".into()), Some(1));
+        nodes.add(Ipv4Addr::new(10, 0, 0, 2), Some("us-nyc".into()), Some(2));
+
+        let node = nodes.get(&Ipv4Addr::new(10, 0, 0, 2)).unwrap();
+        assert_eq!(node.ip(), Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(node.tag().map(String::as_str), Some("us-nyc"));
+        assert_eq!(node.seq(), Some(2));
+        assert!(nodes.get(&Ipv4Addr::new(10, 0, 0, 3)).is_none());
+    }
+
+    #[test]
+    fn test_nodes_tag_diff() {
+        let nodes = Nodes::new();
+        nodes.add(Ipv4Addr::new(10, 0, 0, 1), Some("old".into()), Some(1));
+        nodes.add(Ipv4Addr::new(10, 0, 0, 2), Some("old".into()), Some(2));
+        nodes.add(Ipv4Addr::new(10, 0, 1, 1), Some("new".into()), Some(1));
+
+        let diff = nodes.tag_diff("old", "new");
+        assert_eq!(diff.len(), 2);
+        assert!(diff.iter().all(|node| node.tag().unwrap() == "old"));
+
+        assert_eq!(nodes.tag_symmetric_diff("old", "new").len(), 3);
+        assert!(nodes.tag_diff("missing", "old").is_empty());
+    }
+
+    #[test]
+    fn test_all_with_tag() {
+        let nodes = Nodes::with_shards(3);
+        for i in (1..=6).rev() {
+            let tag = if i % 2 == 0 { "uk-lon" } else { "us-nyc" };
+            nodes.add(Ipv4Addr::new(10, 0, 0, i), Some(tag.into()), Some(1));
+        }
+        nodes.add(Ipv4Addr::new(10, 0, 0, 7), None, None);
+
+        let ips: Vec<Ipv4Addr> = nodes.all_with_tag("uk-lon").iter().map(Node::ip).collect();
+        assert_eq!(
+            ips,
+            vec![
+                Ipv4Addr::new(10, 0, 0, 2),
+                Ipv4Addr::new(10, 0, 0, 4),
+                Ipv4Addr::new(10, 0, 0, 6)
+            ]
+        );
+        assert!(nodes.all_with_tag("missing").is_empty());
+        assert_eq!(
+            nodes.tags(),
+            HashSet::from(["uk-lon".to_string(), "us-nyc".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_nodes_own_ips() {
+        let nodes = Nodes::new();
+        assert!(nodes.is_own_ip(&Ipv4Addr::LOCALHOST));
+        nodes.add_own_ip(Ipv4Addr::new(10, 0, 0, 5));
+        assert!(nodes.is_own_ip(&Ipv4Addr::new(10, 0, 0, 5)));
+        assert_eq!(nodes.own_ips().len(), 2);
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let nodes = Nodes::new();
+        nodes.add(Ipv4Addr::new(10, 0, 0, 2), None, None);
+        nodes.add(Ipv4Addr::new(10, 0, 0, 1), None, None);
+
+        // late subscribers start from the current table
+        let mut snapshots = nodes.snapshots();
+        let ips: Vec<Ipv4Addr> = snapshots
+            .borrow_and_update()
+            .iter()
+            .map(|n| n.ip())
+            .collect();
+        assert_eq!(ips, [1, 2].map(|last| Ipv4Addr::new(10, 0, 0, last)));
+
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        nodes.reap_older_than(Duration::ZERO);
+        assert!(snapshots.has_changed().unwrap());
+        assert!(snapshots.borrow_and_update().is_empty());
+    }
+
+    #[test]
+    fn test_reap_batches_left_events() {
+        let nodes = Nodes::new();
+        let mut events = nodes.events();
+        nodes.add(Ipv4Addr::new(10, 0, 0, 1), None, None);
+        nodes.add(Ipv4Addr::new(10, 0, 0, 2), None, None);
+        std::thread::sleep(std::time::Duration::from_millis(5));
+
+        nodes.reap_older_than(Duration::ZERO);
+        assert!(nodes.all().is_empty());
+
+        assert!(matches!(events.try_recv(), Ok(NodeEvent::Joined(_))));
+        assert!(matches!(events.try_recv(), Ok(NodeEvent::Joined(_))));
+        match events.try_recv() {
+            Ok(NodeEvent::BatchLeft(mut ips)) => {
+                ips.sort();
+                assert_eq!(
+                    ips,
+                    vec![Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)]
+                );
+            }
+            other => panic!("expected BatchLeft, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_pinned_node_survives_reap() {
+        let nodes = Nodes::new();
+        let pinned = Ipv4Addr::new(10, 0, 0, 1);
+        nodes.add(pinned, None, None);
+        nodes.add(Ipv4Addr::new(10, 0, 0, 2), None, None);
+        nodes.pin(pinned, Instant::now() + Duration::from_secs(60));
+        std::thread::sleep(std::time::Duration::from_millis(5));
+
+        nodes.reap_older_than(Duration::ZERO);
+        let all = nodes.all();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].ip(), pinned);
+
+        nodes.unpin(&pinned);
+        nodes.reap_older_than(Duration::ZERO);
+        assert!(nodes.all().is_empty());
+    }
+
+    #[test]
+    fn test_suspended_reaping() {
+        let nodes = Nodes::new();
+        nodes.add(Ipv4Addr::new(10, 0, 0, 1), None, None);
+        std::thread::sleep(std::time::Duration::from_millis(5));
+
+        nodes.suspend_reaping(true);
+        nodes.reap_older_than(Duration::ZERO);
+        assert_eq!(nodes.all().len(), 1);
+
+        nodes.suspend_reaping(false);
+        nodes.reap_older_than(Duration::ZERO);
+        assert!(nodes.all().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_independent_reaper() {
+        let nodes = Arc::new(Nodes::new().with_silence_window(Duration::from_secs(30)));
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+        let reaper = tokio::spawn(run_reaper(
+            Arc::clone(&nodes),
+            Duration::from_secs(10),
+            shutdown_rx,
+        ));
+
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+        nodes.add(ip, None, None);
+        tokio::time::advance(Duration::from_secs(25)).await;
+        tokio::task::yield_now().await;
+        assert!(nodes.contains(&ip));
+
+        // nothing refreshes the node and nothing else calls reap
+        tokio::time::advance(Duration::from_secs(20)).await;
+        tokio::task::yield_now().await;
+        assert!(!nodes.contains(&ip));
+
+        shutdown_tx.send(()).unwrap();
+        reaper.await.unwrap();
+    }
+
+    #[test]
+    fn test_ordered() {
+        let nodes = Nodes::with_shards(3);
+        for last in [9, 2, 7, 1, 5] {
+            nodes.add(Ipv4Addr::new(10, 0, 0, last), None, None);
+        }
+        let ips: Vec<Ipv4Addr> = nodes.ordered().iter().map(|node| node.ip()).collect();
+        assert_eq!(
+            ips,
+            [1, 2, 5, 7, 9].map(|last| Ipv4Addr::new(10, 0, 0, last))
+        );
+    }
+
+    #[test]
+    fn test_tag_limit() {
+        let tag = Some("core".to_string());
+        let nodes = Nodes::new()
+            .with_tag_limit("core", 2)
+            .with_tag_limit_policy(TagLimitPolicy::Reject);
+        for last in 1..=3 {
+            nodes.add(Ipv4Addr::new(10, 0, 0, last), tag.clone(), Some(1));
+        }
+        assert_eq!(nodes.all().len(), 2);
+        assert!(!nodes.contains(&Ipv4Addr::new(10, 0, 0, 3)));
+
+        // refreshing a node already counted doesn't trip the limit
+        nodes.add(Ipv4Addr::new(10, 0, 0, 1), tag.clone(), Some(1));
+        assert_eq!(nodes.all().len(), 2);
+
+        let nodes = Nodes::new().with_tag_limit("core", 2);
+        for last in 1..=3 {
+            nodes.add(Ipv4Addr::new(10, 0, 0, last), tag.clone(), Some(1));
+        }
+        assert_eq!(nodes.all().len(), 3);
+    }
+
+    #[test]
+    fn test_updated_event() {
+        let nodes = Nodes::new();
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+        nodes.add(ip, Some("uk-lon".into()), Some(1));
+        let mut events = nodes.events();
+
+        // a plain refresh changes nothing
+        nodes.add(ip, Some("uk-lon".into()), Some(1));
+        nodes.add(ip, None, None);
+        assert!(events.try_recv().is_err());
+
+        nodes.add(ip, Some("uk-lon".into()), Some(2));
+        match events.try_recv().unwrap() {
+            NodeEvent::Updated {
+                ip: updated,
+                changed_fields,
+            } => {
+                assert_eq!(updated, ip);
+                assert_eq!(changed_fields, vec![NodeField::Seq]);
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+
+        nodes.add_multihomed(vec![ip, Ipv4Addr::new(10, 1, 0, 1)], None, None);
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            NodeEvent::Updated { changed_fields, .. } if changed_fields == vec![NodeField::Addrs]
+        ));
+    }
+
+    #[test]
+    fn test_add_outcome() {
+        let nodes = Nodes::new();
+        let mut rx = nodes.rx();
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+        assert_eq!(nodes.add(ip, None, None), AddOutcome::Inserted);
+        assert_eq!(nodes.add(ip, None, None), AddOutcome::Unchanged);
+        let seen = nodes.get(&ip).unwrap().last_seen();
+
+        assert_eq!(
+            nodes.add(ip, Some("uk-lon".into()), Some(1)),
+            AddOutcome::Updated
+        );
+        let node = nodes.get(&ip).unwrap();
+        assert_eq!(node.tag().map(String::as_str), Some("uk-lon"));
+        assert_eq!(node.seq(), Some(1));
+        assert!(node.last_seen() >= seen);
+
+        // only the insert is announced on rx
+        assert!(rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_err());
+
+        let nodes = Nodes::new().with_confirmation_threshold(2);
+        assert_eq!(nodes.add(ip, None, None), AddOutcome::NotAdded);
+        assert_eq!(nodes.add(ip, None, None), AddOutcome::Inserted);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for() {
+        let nodes = Arc::new(Nodes::new());
+        nodes.add(Ipv4Addr::new(10, 0, 0, 1), None, None);
+        assert!(nodes.wait_for(1, Duration::ZERO).await);
+        assert!(!nodes.wait_for(2, Duration::from_millis(10)).await);
+
+        let adder = Arc::clone(&nodes);
+        tokio::spawn(async move {
+            for i in 2..=3 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                adder.add(Ipv4Addr::new(10, 0, 0, i), None, None);
+            }
         });
+        assert!(nodes.wait_for(3, Duration::from_secs(1)).await);
+    }
+
+    #[tokio::test]
+    async fn test_supervised_tasks_stop_together() {
+        let mut tasks = Tasks::default();
+        tasks.spawn(async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            panic!("broadcast task died");
+        });
+        tasks.spawn(std::future::pending());
+        tasks.supervise();
+
+        let result = tokio::time::timeout(Duration::from_secs(1), tasks.join()).await;
+        assert!(matches!(result, Ok(Err(DiscoveryError::TaskFailed(_)))));
+    }
+
+    #[test]
+    fn test_remove() {
+        let nodes = Nodes::new();
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+        nodes.add(ip, None, None);
+        let mut events = nodes.events();
+
+        assert!(nodes.remove(ip));
+        assert!(!nodes.contains(&ip));
+        assert!(matches!(events.try_recv(), Ok(NodeEvent::Removed(removed)) if removed == ip));
+
+        assert!(!nodes.remove(ip));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_event_stream_skips_lag() {
+        let nodes = Nodes::new().with_channel_capacity(2);
+        let mut stream = Box::pin(nodes.event_stream());
+        for i in 1..=4 {
+            nodes.add(Ipv4Addr::new(10, 0, 0, i), None, None);
+        }
+        nodes.remove(Ipv4Addr::new(10, 0, 0, 1));
+
+        // the first three joins were overwritten; the rest arrive in order
+        assert!(
+            matches!(stream.next().await, Some(NodeEvent::Joined(node)) if node.ip() == Ipv4Addr::new(10, 0, 0, 4))
+        );
+        assert!(
+            matches!(stream.next().await, Some(NodeEvent::Removed(ip)) if ip == Ipv4Addr::new(10, 0, 0, 1))
+        );
+
+        drop(nodes);
+        assert!(stream.next().await.is_none());
+    }
+
+    #[test]
+    fn test_event_removed() {
+        let a = Ipv4Addr::new(10, 0, 0, 1);
+        let b = Ipv4Addr::new(10, 0, 0, 2);
+        assert_eq!(NodeEvent::Left(a).removed(), &[a]);
+        assert_eq!(NodeEvent::Removed(a).removed(), &[a]);
+        assert_eq!(NodeEvent::BatchLeft(vec![a, b]).removed(), &[a, b]);
+
+        let nodes = Nodes::new();
+        let mut events = nodes.events();
+        nodes.add(a, None, None);
+        assert!(events.try_recv().unwrap().removed().is_empty());
+    }
+
+    #[test]
+    fn test_is_stale() {
+        let nodes = Nodes::new();
+        nodes.add(Ipv4Addr::new(10, 0, 0, 1), None, None);
+        let node = nodes.all().remove(0);
+        assert!(!node.is_stale(Duration::from_secs(60)));
+        assert!(node.last_seen() <= Instant::now());
+
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert!(node.is_stale(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_ipv6_addrs_survive_refresh() {
+        let nodes = Nodes::new();
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+        let ipv6: Ipv6Addr = "fd00::1".parse().unwrap();
+        assert!(!nodes.set_ipv6_addrs(&ip, vec![ipv6]));
+
+        nodes.add(ip, None, None);
+        assert!(nodes.set_ipv6_addrs(&ip, vec![ipv6]));
+        nodes.add(ip, None, None);
+        assert_eq!(nodes.all()[0].ipv6_addrs(), &[ipv6]);
+    }
+
+    #[test]
+    fn test_ipv6_only_nodes() {
+        let nodes = Nodes::new();
+        let ip: Ipv6Addr = "fd00::5".parse().unwrap();
+        let second: Ipv6Addr = "fd00::6".parse().unwrap();
+        assert!(nodes.add_ipv6_only(vec![ip, second], "uk-lon".into(), 1, "wavey.io".into()));
+        assert!(!nodes.add_ipv6_only(vec![ip], "uk-lon".into(), 2, "wavey.io".into()));
+
+        let v6 = nodes.ipv6_only();
+        assert_eq!(v6.len(), 1);
+        assert_eq!((v6[0].ip(), v6[0].seq()), (ip, 2));
+        // kept apart from the IPv4 table
+        assert!(nodes.is_empty());
+
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        nodes.reap_older_than(Duration::ZERO);
+        assert!(nodes.ipv6_only().is_empty());
+    }
+
+    #[test]
+    fn test_pick_round_robin_cycles() {
+        let nodes = Nodes::new()
+            .with_own_ips([Ipv4Addr::new(10, 0, 0, 9)])
+            .with_rng_seed(7);
+        assert!(nodes.pick_round_robin().is_none());
+        for i in [3, 1, 2, 9] {
+            nodes.add(Ipv4Addr::new(10, 0, 0, i), None, None);
+        }
+
+        // every peer once per round, in IP order, and never ourselves
+        let picked: Vec<u8> = (0..6)
+            .map(|_| nodes.pick_round_robin().unwrap().ip().octets()[3])
+            .collect();
+        assert_eq!(picked, [1, 2, 3, 1, 2, 3]);
+
+        // unhealthy nodes drop out of the rotation
+        nodes.set_healthy(&Ipv4Addr::new(10, 0, 0, 2), false);
+        let picked: HashSet<u8> = (0..4)
+            .map(|_| nodes.pick_round_robin().unwrap().ip().octets()[3])
+            .collect();
+        assert_eq!(picked, HashSet::from([1, 3]));
+
+        let random: Vec<u8> = (0..8)
+            .map(|_| nodes.pick_random().unwrap().ip().octets()[3])
+            .collect();
+        assert_eq!(random, [1, 1, 3, 1, 1, 3, 3, 1]);
+    }
+
+    #[test]
+    fn test_pick_weighted() {
+        let nodes = Nodes::new().with_rng_seed(7);
+        let (light, heavy, unweighted) = (
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(10, 0, 0, 2),
+            Ipv4Addr::new(10, 0, 0, 3),
+        );
+        for ip in [light, heavy, unweighted] {
+            nodes.add(ip, None, None);
+        }
+        assert!(nodes.pick_weighted().is_none());
+
+        // published in TXT metadata rather than SRV
+        nodes.set_metadata(&light, HashMap::from([("weight".into(), "1".into())]));
+        nodes.set_metadata(&heavy, HashMap::from([("weight".into(), "3".into())]));
+        assert_eq!(nodes.get(&heavy).unwrap().weight(), Some(3));
+
+        let picked: Vec<Ipv4Addr> = (0..12)
+            .map(|_| nodes.pick_weighted().unwrap().ip())
+            .collect();
+        assert_eq!(
+            picked,
+            [light, heavy, light, heavy, heavy, heavy, heavy, heavy, heavy, light, light, light]
+        );
+        assert!(!picked.contains(&unweighted));
+    }
+
+    #[test]
+    fn test_pick_weighted_prefers_priority() {
+        let nodes = Nodes::new().with_rng_seed(7);
+        let (light, heavy, backup, unprioritised) = (
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(10, 0, 0, 2),
+            Ipv4Addr::new(10, 0, 0, 3),
+            Ipv4Addr::new(10, 0, 0, 4),
+        );
+        for ip in [light, heavy, backup, unprioritised] {
+            nodes.add(ip, None, None);
+        }
+        assert!(nodes.set_srv(&light, 80, 10, 1));
+        assert!(nodes.set_srv(&heavy, 80, 10, 3));
+        assert!(nodes.set_srv(&backup, 80, 20, 100));
+        nodes.set_metadata(
+            &unprioritised,
+            HashMap::from([("weight".into(), "100".into())]),
+        );
+
+        // the same draws as in test_pick_weighted: the backup and the
+        // unprioritised node don't dilute the primaries' weights
+        let picked: Vec<Ipv4Addr> = (0..12)
+            .map(|_| nodes.pick_weighted().unwrap().ip())
+            .collect();
+        assert_eq!(
+            picked,
+            [light, heavy, light, heavy, heavy, heavy, heavy, heavy, heavy, light, light, light]
+        );
+
+        nodes.set_healthy(&light, false);
+        nodes.set_healthy(&heavy, false);
+        assert_eq!(nodes.pick_weighted().unwrap().ip(), backup);
+    }
+
+    #[test]
+    fn test_srv_priority() {
+        let nodes = Nodes::new();
+        for i in 1..=3 {
+            nodes.add(Ipv4Addr::new(10, 0, 0, i), None, None);
+        }
+        assert!(nodes.set_srv(&Ipv4Addr::new(10, 0, 0, 2), 8080, 10, 0));
+        assert!(nodes.set_srv(&Ipv4Addr::new(10, 0, 0, 3), 8081, 5, 0));
+        assert!(!nodes.set_srv(&Ipv4Addr::new(10, 0, 0, 4), 8082, 0, 0));
+        // a refresh without SRV data keeps it
+        nodes.add(Ipv4Addr::new(10, 0, 0, 3), None, None);
+
+        let ordered: Vec<(Ipv4Addr, Option<u16>)> = nodes
+            .by_priority()
+            .iter()
+            .map(|n| (n.ip(), n.port()))
+            .collect();
+        assert_eq!(
+            ordered,
+            vec![
+                (Ipv4Addr::new(10, 0, 0, 3), Some(8081)),
+                (Ipv4Addr::new(10, 0, 0, 2), Some(8080)),
+                (Ipv4Addr::new(10, 0, 0, 1), None),
+            ]
+        );
+
+        let mut events = nodes.events();
+        assert!(nodes.set_srv(&Ipv4Addr::new(10, 0, 0, 3), 8081, 5, 0));
+        assert!(events.try_recv().is_err());
+        assert!(nodes.set_srv(&Ipv4Addr::new(10, 0, 0, 3), 8081, 1, 0));
+        assert!(matches!(
+            events.try_recv(),
+            Ok(NodeEvent::Updated { changed_fields, .. }) if changed_fields == vec![NodeField::Priority]
+        ));
+        assert!(nodes.set_srv(&Ipv4Addr::new(10, 0, 0, 3), 9091, 5, 0));
+        assert!(matches!(
+            events.try_recv(),
+            Ok(NodeEvent::Updated { changed_fields, .. })
+                if changed_fields == vec![NodeField::Port, NodeField::Priority]
+        ));
+    }
+
+    #[test]
+    fn test_metadata_change_updates() {
+        let nodes = Nodes::new();
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+        let metadata = HashMap::from([("region".to_string(), "eu".to_string())]);
+        assert!(!nodes.set_metadata(&ip, metadata.clone()));
+
+        nodes.add(ip, None, None);
+        let mut events = nodes.events();
+        assert!(nodes.set_metadata(&ip, metadata.clone()));
+        assert!(matches!(
+            events.try_recv(),
+            Ok(NodeEvent::Updated { changed_fields, .. }) if changed_fields == vec![NodeField::Metadata]
+        ));
+        // every scan republishes the same TXT records; only changes are news
+        assert!(nodes.set_metadata(&ip, metadata.clone()));
+        assert!(events.try_recv().is_err());
+        assert_eq!(nodes.get(&ip).unwrap().metadata(), &metadata);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_node_serde_round_trip() {
+        let nodes = Nodes::new();
+        let ip = Ipv4Addr::new(10, 0, 0, 2);
+        nodes.add(ip, Some("uk-lon".into()), Some(3));
+        nodes.annotate(&ip, "drained", "yes");
+        let node = nodes.get(&ip).unwrap();
+
+        let json = serde_json::to_string(&node).unwrap();
+        assert_eq!(json, r#"{"ip":"10.0.0.2","tag":"uk-lon","seq":3}"#);
+
+        let back: Node = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.ip(), ip);
+        assert_eq!(back.addrs(), &[ip]);
+        assert_eq!(back.tag().map(String::as_str), Some("uk-lon"));
+        assert_eq!(back.seq(), Some(3));
+        assert_eq!(back.annotation("drained"), None);
+
+        let untagged: Node =
+            serde_json::from_str(r#"{"ip":"10.0.0.9","tag":null,"seq":null}"#).unwrap();
+        assert_eq!(untagged.tag(), None);
+    }
+
+    #[test]
+    fn test_history_drops_oldest() {
+        let nodes = Nodes::new().with_history_capacity(3);
+        for i in 1..=4 {
+            nodes.add(Ipv4Addr::new(10, 0, 0, i), None, None);
+        }
+        nodes.remove(Ipv4Addr::new(10, 0, 0, 1));
+
+        let history = nodes.history();
+        assert_eq!(history.len(), 3);
+        assert!(
+            matches!(&history[0].1, NodeEvent::Joined(node) if node.ip() == Ipv4Addr::new(10, 0, 0, 3))
+        );
+        assert!(
+            matches!(&history[1].1, NodeEvent::Joined(node) if node.ip() == Ipv4Addr::new(10, 0, 0, 4))
+        );
+        assert!(matches!(history[2].1, NodeEvent::Removed(ip) if ip == Ipv4Addr::new(10, 0, 0, 1)));
+        assert!(history.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+
+        let nodes = Nodes::new().with_history_capacity(0);
+        nodes.add(Ipv4Addr::new(10, 0, 0, 1), None, None);
+        assert!(nodes.history().is_empty());
+    }
+
+    #[test]
+    fn test_channel_stats() {
+        let nodes = Nodes::new();
+        let mut rx = nodes.rx();
+        nodes.add(Ipv4Addr::new(10, 0, 0, 1), None, None);
+        nodes.add(Ipv4Addr::new(10, 0, 0, 2), None, None);
+
+        let stats = nodes.rx_stats();
+        assert_eq!(stats.backlog, 2);
+        assert_eq!(stats.receivers, 1);
+        assert_eq!(stats.capacity, CHANNEL_CAPACITY);
+
+        rx.try_recv().unwrap();
+        rx.try_recv().unwrap();
+        assert_eq!(nodes.rx_stats().backlog, 0);
+        assert_eq!(nodes.events_stats().receivers, 0);
+
+        let nodes = Nodes::new().with_channel_capacity(64);
+        assert_eq!(nodes.events_stats().capacity, 64);
+        let mut events = nodes.events();
+        for i in 1..=40 {
+            nodes.add(Ipv4Addr::new(10, 0, 0, i), None, None);
+        }
+        assert!(matches!(events.try_recv(), Ok(NodeEvent::Joined(_))));
+    }
+
+    #[test]
+    fn test_own_ip_setup() {
+        let nodes = Nodes::new().with_own_ips([Ipv4Addr::new(10, 0, 0, 5)]);
+        assert!(nodes.is_own_ip(&Ipv4Addr::new(10, 0, 0, 5)));
+        assert!(nodes.is_own_ip(&Ipv4Addr::LOCALHOST));
+
+        let nodes = Nodes::new().with_loopback_own_ip(false);
+        assert!(nodes.own_ips().is_empty());
+    }
+
+    #[test]
+    fn test_confirmation_threshold() {
+        let nodes = Nodes::new().with_confirmation_threshold(3);
+        let mut events = nodes.events();
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+
+        nodes.add(ip, None, None);
+        nodes.add(ip, None, None);
+        assert!(!nodes.contains(&ip));
+        assert!(events.try_recv().is_err());
+
+        nodes.add(ip, None, None);
+        assert!(nodes.contains(&ip));
+        assert!(matches!(events.try_recv(), Ok(NodeEvent::Joined(_))));
+    }
+
+    #[test]
+    fn test_seed_skips_confirmation() {
+        let nodes = Nodes::new()
+            .with_confirmation_threshold(3)
+            .with_tag_limit("api", 0)
+            .with_tag_limit_policy(TagLimitPolicy::Reject);
+        let mut events = nodes.events();
+        let seed = Ipv4Addr::new(10, 0, 0, 1);
+
+        assert_eq!(
+            nodes.insert_unchecked(seed, Some("api".into()), Some(1)),
+            AddOutcome::Inserted
+        );
+        assert!(nodes.contains(&seed));
+        assert!(matches!(events.try_recv(), Ok(NodeEvent::Joined(_))));
+        // the seed wasn't a sighting, so a discovered node still needs three
+        let other = Ipv4Addr::new(10, 0, 0, 2);
+        nodes.insert_unchecked(other, None, None);
+        nodes.remove(other);
+        nodes.add(other, None, None);
+        nodes.add(other, None, None);
+        assert!(!nodes.contains(&other));
+    }
+
+    #[test]
+    fn test_annotations_survive_refresh() {
+        let nodes = Nodes::new();
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+        assert!(!nodes.annotate(&ip, "state", "maintenance"));
+
+        nodes.add(ip, None, None);
+        assert!(nodes.annotate(&ip, "state", "maintenance"));
+        nodes.add(ip, Some("uk-lon".into()), Some(1));
+
+        let node = nodes.all().pop().unwrap();
+        assert_eq!(node.annotation("state"), Some("maintenance"));
+        assert_eq!(node.annotation("missing"), None);
+    }
+
+    #[test]
+    fn test_refresh_keeps_known_metadata() {
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+
+        // learnt via DNS first, then refreshed by a VLAN announcement
+        let nodes = Nodes::new();
+        nodes.add(ip, Some("uk-lon".into()), Some(2));
+        nodes.add(ip, None, None);
+        let node = nodes.all().pop().unwrap();
+        assert_eq!(node.tag().map(String::as_str), Some("uk-lon"));
+        assert_eq!(node.seq(), Some(2));
+
+        let nodes = Nodes::new().with_clear_missing_metadata(true);
+        nodes.add(ip, Some("uk-lon".into()), Some(2));
+        nodes.add(ip, None, None);
+        let node = nodes.all().pop().unwrap();
+        assert_eq!(node.tag(), None);
+        assert_eq!(node.seq(), None);
+    }
+
+    #[test]
+    fn test_contains_all() {
+        let nodes = Nodes::with_shards(2);
+        nodes.add(Ipv4Addr::new(10, 0, 0, 1), None, None);
+        nodes.add(Ipv4Addr::new(10, 0, 0, 2), None, None);
+
+        let present = nodes.contains_all(&[
+            Ipv4Addr::new(10, 0, 0, 2),
+            Ipv4Addr::new(10, 0, 0, 3),
+            Ipv4Addr::new(10, 0, 0, 1),
+        ]);
+        assert_eq!(present, vec![true, false, true]);
+    }
+
+    #[test]
+    fn test_nodes_add_multihomed() {
+        let nodes = Nodes::new();
+        let primary = Ipv4Addr::new(10, 0, 0, 1);
+        let secondary = Ipv4Addr::new(10, 1, 0, 1);
+        nodes.add_multihomed(vec![primary, secondary], None, None);
+
+        let all = nodes.all();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].ip(), primary);
+        assert_eq!(all[0].addrs(), &[primary, secondary]);
+        assert!(!nodes.contains(&secondary));
     }
 }
@@ -1,8 +1,36 @@
-use discovery::{dns::discover, vlan};
+use discovery::{
+    dns::{discover_once, DnsConfig},
+    vlan::{self, Ipv4Cidr, VlanConfig},
+};
 use std::collections::HashSet;
-use std::net::{Shutdown, SocketAddr};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::str::FromStr;
 use structopt::StructOpt;
 
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    /// Space-separated IPs.
+    Plain,
+    /// An array of `{"ip", "tag", "seq"}` objects.
+    #[cfg(feature = "serde")]
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(Format::Plain),
+            #[cfg(feature = "serde")]
+            "json" => Ok(Format::Json),
+            #[cfg(not(feature = "serde"))]
+            "json" => Err("json output needs the serde feature".into()),
+            _ => Err(format!("unknown format {:?}, expected plain or json", s)),
+        }
+    }
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = "discovery", about = "A tool for discovering services")]
 enum Command {
@@ -18,10 +46,30 @@ enum Command {
 
         #[structopt(long, default_value = "8.8.8.8:53")]
         dns_server: String,
+
+        /// `plain` or `json`.
+        #[structopt(long, default_value = "plain")]
+        format: Format,
     },
     Vlan {
         #[structopt(long, default_value = "12345")]
         broadcast_port: u16,
+
+        /// Announce from this interface's address.
+        #[structopt(long)]
+        interface: Option<String>,
+
+        /// Only use addresses, and accept peers, within this network.
+        #[structopt(long)]
+        subnet: Option<Ipv4Cidr>,
+
+        /// Announce from this address instead of the first private one.
+        #[structopt(long)]
+        own_ip: Option<Ipv4Addr>,
+
+        /// Start even if our startup broadcast never echoes back.
+        #[structopt(long)]
+        skip_probe: bool,
     },
 }
 
@@ -30,10 +78,31 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let args = Command::from_args();
 
     match args {
-        Command::Vlan { broadcast_port } => {
-            let (_up, _fin, _shutodwn_tx, nodes) = vlan::discover(broadcast_port).await.unwrap();
-            while let Ok(ip) = nodes.rx().recv().await {
-                dbg!(ip);
+        Command::Vlan {
+            broadcast_port,
+            interface,
+            subnet,
+            own_ip,
+            skip_probe,
+        } => {
+            let mut config = VlanConfig {
+                preferred_interfaces: interface.into_iter().collect(),
+                allowed_networks: subnet.into_iter().collect(),
+                own_ip,
+                ..Default::default()
+            };
+            if skip_probe {
+                config.probe_timeout = None;
+            }
+            let handle = vlan::discover(broadcast_port, config).await?;
+            let mut rx = handle.nodes.rx();
+            while let Ok(node) = rx.recv().await {
+                println!(
+                    "{} tag={} seq={}",
+                    node.ip(),
+                    node.tag().map_or("-", String::as_str),
+                    node.seq().map_or("-".to_string(), |seq| seq.to_string())
+                );
             }
         }
         Command::Dns {
@@ -41,19 +110,33 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             domain,
             prefix,
             tags,
+            format,
         } => {
             let dns_server: SocketAddr = dns_server.parse()?;
             let tags: Vec<String> = tags.split(',').map(|s| s.to_string()).collect();
             let mut uniq_ips = HashSet::new();
 
-            let (up_rx, fin_rx, shutdown_rx, nodes) =
-                discover(vec![], dns_server, domain, prefix, tags)
-                    .await
-                    .unwrap();
+            let domains = domain.split(',').map(str::trim);
+            let nodes = discover_once(
+                Vec::<String>::new(),
+                dns_server,
+                domains,
+                prefix,
+                tags,
+                DnsConfig::default(),
+            )
+            .await?;
 
-            let _ = up_rx.await;
+            match format {
+                #[cfg(feature = "serde")]
+                Format::Json => {
+                    println!("{}", serde_json::to_string(&nodes)?);
+                    return Ok(());
+                }
+                Format::Plain => {}
+            }
 
-            for node in &nodes.all() {
+            for node in &nodes {
                 uniq_ips.insert(node.ip());
             }
 
@@ -66,8 +149,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                     .collect::<Vec<String>>()
                     .join(" ")
             );
-
-            let _ = shutdown_rx.send(());
         }
     }
 
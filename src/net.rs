@@ -0,0 +1,169 @@
+use if_addrs::{get_if_addrs, Interface};
+use std::net::{IpAddr, Ipv4Addr};
+use tracing::warn;
+
+/// Every interface address on this host; an enumeration failure is logged
+/// and treated as no interfaces.
+pub(crate) fn interfaces() -> Vec<Interface> {
+    match get_if_addrs() {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            warn!("Failed to get network interfaces: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// The first IPv4 address on `interface`, see [`get_ipv4s`].
+pub fn get_ip(interface: &str) -> Option<Ipv4Addr> {
+    get_ipv4s(interface).into_iter().next()
+}
+
+/// Every IPv4 address on `interface`; [`get_ips`] restricted to v4.
+pub fn get_ipv4s(interface: &str) -> Vec<Ipv4Addr> {
+    get_ips(interface)
+        .into_iter()
+        .filter_map(|ip| match ip {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        })
+        .collect()
+}
+
+/// Every address, v4 and v6, on `interface`, in enumeration order. The name
+/// is trimmed and, failing an exact match, compared ignoring case. Logs the
+/// available interface names when nothing matches.
+pub fn get_ips(interface: &str) -> Vec<IpAddr> {
+    let addrs = interfaces();
+    let found = ips_named(&addrs, interface);
+
+    if found.is_empty() {
+        let mut names: Vec<&str> = addrs.iter().map(|addr| addr.name.as_str()).collect();
+        names.sort_unstable();
+        names.dedup();
+        warn!(
+            "No address on interface {:?}; available interfaces: {}",
+            interface,
+            names.join(", ")
+        );
+    }
+
+    found
+}
+
+fn ips_named(addrs: &[Interface], interface: &str) -> Vec<IpAddr> {
+    // tolerate stray whitespace and, failing an exact match, wrong case
+    let wanted = interface.trim();
+    let collect = |exact: bool| {
+        let mut ips = Vec::new();
+        for addr in addrs {
+            let name_matches = if exact {
+                addr.name == wanted
+            } else {
+                addr.name.eq_ignore_ascii_case(wanted)
+            };
+            let ip = addr.ip();
+            if name_matches && !ips.contains(&ip) {
+                ips.push(ip);
+            }
+        }
+        ips
+    };
+    let exact = collect(true);
+    if exact.is_empty() {
+        collect(false)
+    } else {
+        exact
+    }
+}
+
+/// Every non-loopback IPv4 address on this host, whatever the interface.
+pub(crate) fn local_ips() -> Vec<Ipv4Addr> {
+    let mut ips = Vec::new();
+    for addr in interfaces() {
+        if let IpAddr::V4(ip) = addr.ip() {
+            if !ip.is_loopback() && !ips.contains(&ip) {
+                ips.push(ip);
+            }
+        }
+    }
+    ips
+}
+
+/// The first of `names` that isn't an interface on this host.
+pub(crate) fn missing_interface(names: &[String]) -> Option<String> {
+    if names.is_empty() {
+        return None;
+    }
+    let addrs = interfaces();
+    if addrs.is_empty() {
+        return None;
+    }
+    names
+        .iter()
+        .find(|name| !addrs.iter().any(|addr| addr.name == **name))
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::net::Ipv6Addr;
+
+    fn loopback_name() -> Option<String> {
+        interfaces()
+            .into_iter()
+            .find(|addr| addr.ip() == IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .map(|addr| addr.name)
+    }
+
+    #[test]
+    fn test_get_ipv4s_on_loopback() {
+        let Some(name) = loopback_name() else {
+            return;
+        };
+
+        let ips = get_ipv4s(&name);
+        assert!(ips.contains(&Ipv4Addr::LOCALHOST));
+        assert_eq!(get_ip(&name), ips.first().copied());
+
+        // whitespace and case are forgiven
+        let sloppy = format!(" {} ", name.to_ascii_uppercase());
+        assert_eq!(get_ipv4s(&sloppy), ips);
+
+        assert!(get_ipv4s("no-such-interface0").is_empty());
+        assert_eq!(get_ip("no-such-interface0"), None);
+    }
+
+    #[test]
+    fn test_get_ips_on_loopback() {
+        let Some(name) = loopback_name() else {
+            return;
+        };
+
+        let ips = get_ips(&name);
+        assert!(ips.contains(&IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        // every address is listed once, and the v4 view is a subset in order
+        let unique: HashSet<&IpAddr> = ips.iter().collect();
+        assert_eq!(unique.len(), ips.len());
+        let v4: Vec<IpAddr> = get_ipv4s(&name).into_iter().map(IpAddr::V4).collect();
+        let filtered: Vec<IpAddr> = ips.iter().copied().filter(IpAddr::is_ipv4).collect();
+        assert_eq!(v4, filtered);
+        // a dual-stack loopback carries ::1 alongside 127.0.0.1
+        if interfaces()
+            .iter()
+            .any(|addr| addr.name == name && addr.ip() == IpAddr::V6(Ipv6Addr::LOCALHOST))
+        {
+            assert!(ips.contains(&IpAddr::V6(Ipv6Addr::LOCALHOST)));
+            assert!(ips.len() >= 2);
+        }
+
+        assert!(get_ips("no-such-interface0").is_empty());
+    }
+
+    #[test]
+    fn test_local_ips_skip_loopback() {
+        assert!(local_ips().iter().all(|ip| !ip.is_loopback()));
+    }
+}
@@ -0,0 +1,153 @@
+//! Saving the node table to disk so a restart warm-starts with the peers we
+//! last knew about instead of an empty table. Needs the `serde` feature; the
+//! file is a JSON array of [`Node`]s.
+
+use crate::{Node, Nodes};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::time::{sleep, Duration};
+use tracing::{debug, info, warn};
+
+/// Where to keep the node table, and how often to write it.
+#[derive(Debug, Clone)]
+pub struct Persistence {
+    pub path: PathBuf,
+    pub interval: Duration,
+}
+
+impl Persistence {
+    /// Saves to `path` every 30 seconds.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Persistence {
+            path: path.into(),
+            interval: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Nodes {
+    /// Writes every peer to `path`, leaving ourselves out. The file is
+    /// written beside `path` and renamed over it, so a crash mid-write leaves
+    /// the previous snapshot intact.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let peers: Vec<Node> = self.all().into_iter().filter(|n| !n.is_self()).collect();
+        let json = serde_json::to_vec(&peers)?;
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, json)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    /// Adds the peers saved at `path` as if they were seeds: each is stamped
+    /// as just seen, so it stays for one silence window and is reaped then
+    /// unless discovery finds it again. A missing file restores nothing; an
+    /// unreadable or corrupt one is logged and skipped. Returns how many
+    /// nodes were restored.
+    pub fn restore(&self, path: &Path) -> usize {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                debug!("No saved nodes at {}", path.display());
+                return 0;
+            }
+            Err(e) => {
+                warn!("Failed to read saved nodes from {}: {}", path.display(), e);
+                return 0;
+            }
+        };
+        let saved: Vec<Node> = match serde_json::from_slice(&bytes) {
+            Ok(saved) => saved,
+            Err(e) => {
+                warn!("Ignoring corrupt node file {}: {}", path.display(), e);
+                return 0;
+            }
+        };
+
+        let mut restored = 0;
+        for node in saved {
+            if self.is_own_ip(&node.ip()) || self.contains(&node.ip()) {
+                continue;
+            }
+            self.insert_unchecked(node.ip(), node.tag().cloned(), node.seq());
+            restored += 1;
+        }
+        info!("Restored {} nodes from {}", restored, path.display());
+        restored
+    }
+}
+
+/// Saves `nodes` every `persistence.interval`, and once more when `shutdown`
+/// fires.
+pub(crate) async fn run_persister(
+    nodes: Arc<Nodes>,
+    persistence: Persistence,
+    mut shutdown: watch::Receiver<()>,
+) {
+    loop {
+        let stopping = tokio::select! {
+            _ = shutdown.changed() => true,
+            _ = sleep(persistence.interval) => false,
+        };
+        let nodes = Arc::clone(&nodes);
+        let path = persistence.path.clone();
+        match tokio::task::spawn_blocking(move || nodes.save(&path)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!(
+                "Failed to save nodes to {}: {}",
+                persistence.path.display(),
+                e
+            ),
+            Err(e) => warn!("Saving nodes panicked: {}", e),
+        }
+        if stopping {
+            info!("Shutdown signal received, stopping persister task");
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("discovery-{}-{}.json", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_save_and_restore() {
+        let path = temp_path("save");
+        let nodes = Nodes::new().with_own_ips([Ipv4Addr::new(10, 0, 0, 1)]);
+        nodes.add(Ipv4Addr::new(10, 0, 0, 1), None, None);
+        nodes.add(Ipv4Addr::new(10, 0, 0, 2), Some("api".into()), Some(1));
+        nodes.add(Ipv4Addr::new(10, 0, 0, 3), Some("db".into()), None);
+        nodes.save(&path).unwrap();
+
+        let restored = Nodes::new();
+        assert_eq!(restored.restore(&path), 2);
+        // we weren't saved, only our peers
+        assert!(!restored.contains(&Ipv4Addr::new(10, 0, 0, 1)));
+        let api = restored.get(&Ipv4Addr::new(10, 0, 0, 2)).unwrap();
+        assert_eq!(api.tag().map(String::as_str), Some("api"));
+        assert_eq!(api.seq(), Some(1));
+        assert!(restored.contains(&Ipv4Addr::new(10, 0, 0, 3)));
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_restore_tolerates_bad_files() {
+        let nodes = Nodes::new();
+        assert_eq!(nodes.restore(&temp_path("missing")), 0);
+
+        let path = temp_path("corrupt");
+        std::fs::write(&path, r#"[{"ip":"10.0.0.2","tag":"api","#).unwrap();
+        assert_eq!(nodes.restore(&path), 0);
+        assert!(nodes.is_empty());
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
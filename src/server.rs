@@ -1,17 +1,63 @@
 use std::io;
+use std::net::SocketAddr;
 use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use tracing::{debug, info};
 
-pub async fn run_server(addr: &str) -> io::Result<()> {
+/// Answers datagrams on `addr` with whatever `handler` returns for them until
+/// `shutdown` fires; a `None` from the handler sends no reply.
+pub async fn run_server<F>(
+    addr: &str,
+    handler: F,
+    mut shutdown: watch::Receiver<()>,
+) -> io::Result<()>
+where
+    F: Fn(&[u8], SocketAddr) -> Option<Vec<u8>> + Send + Sync,
+{
     let socket = UdpSocket::bind(addr).await?;
-    println!("Server running on {}", addr);
+    info!("Server running on {}", addr);
 
     let mut buf = [0; 1024];
 
     loop {
-        let (len, addr) = socket.recv_from(&mut buf).await?;
-        println!("Received from {}: {:?}", addr, &buf[..len]);
+        tokio::select! {
+            _ = shutdown.changed() => {
+                info!("Shutdown signal received, stopping server");
+                return Ok(());
+            }
+            result = socket.recv_from(&mut buf) => {
+                let (len, addr) = result?;
+                debug!("Received from {}: {:?}", addr, &buf[..len]);
 
-        let response = b"hello";
-        socket.send_to(response, addr).await?;
+                if let Some(response) = handler(&buf[..len], addr) {
+                    socket.send_to(&response, addr).await?;
+                }
+            }
+        }
+    }
+}
+
+/// Replies `hello` to every datagram.
+pub async fn run_echo_server(addr: &str, shutdown: watch::Receiver<()>) -> io::Result<()> {
+    run_server(addr, |_, _| Some(b"hello".to_vec()), shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+        let server = tokio::spawn(run_echo_server("127.0.0.1:0", shutdown_rx));
+
+        shutdown_tx.send(()).unwrap();
+        timeout(Duration::from_secs(1), server)
+            .await
+            .expect("server should stop on shutdown")
+            .unwrap()
+            .unwrap();
     }
 }
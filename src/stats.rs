@@ -0,0 +1,76 @@
+//! Metric names reported through the [`metrics`](https://docs.rs/metrics)
+//! facade when the `metrics` feature is on. Install any recorder, e.g.
+//! `metrics-exporter-prometheus`, to scrape them. With the feature off
+//! nothing is recorded and the hooks compile away.
+//!
+//! | Name | Kind | Labels |
+//! |------|------|--------|
+//! | [`NODES`] | gauge | |
+//! | [`NODES_ADDED`] | counter | |
+//! | [`NODES_REAPED`] | counter | |
+//! | [`DNS_QUERIES`] | counter | `outcome`: `success`, `failure` or `timeout` |
+//! | [`VLAN_PACKETS`] | counter | `direction`: `received` or `sent` |
+
+use crate::Nodes;
+use std::io;
+
+/// Nodes in the table, ourselves included.
+pub const NODES: &str = "discovery_nodes";
+/// Nodes inserted, seeds included; refreshes don't count.
+pub const NODES_ADDED: &str = "discovery_nodes_added_total";
+/// Nodes dropped for staying silent past the silence window.
+pub const NODES_REAPED: &str = "discovery_nodes_reaped_total";
+/// DNS questions that got an answer, failed, or timed out after every retry.
+pub const DNS_QUERIES: &str = "discovery_dns_queries_total";
+/// VLAN datagrams received and sent, announcements and goodbyes alike.
+pub const VLAN_PACKETS: &str = "discovery_vlan_packets_total";
+
+#[cfg(feature = "metrics")]
+pub(crate) fn node_count(nodes: &Nodes) {
+    metrics::gauge!(NODES).set(nodes.len() as f64);
+}
+
+#[cfg(not(feature = "metrics"))]
+#[inline(always)]
+pub(crate) fn node_count(_nodes: &Nodes) {}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn node_added() {
+    metrics::counter!(NODES_ADDED).increment(1);
+}
+
+#[cfg(not(feature = "metrics"))]
+#[inline(always)]
+pub(crate) fn node_added() {}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn nodes_reaped(count: usize) {
+    metrics::counter!(NODES_REAPED).increment(count as u64);
+}
+
+#[cfg(not(feature = "metrics"))]
+#[inline(always)]
+pub(crate) fn nodes_reaped(_count: usize) {}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn dns_query<T>(result: &io::Result<T>) {
+    let outcome = match result {
+        Ok(_) => "success",
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => "timeout",
+        Err(_) => "failure",
+    };
+    metrics::counter!(DNS_QUERIES, "outcome" => outcome).increment(1);
+}
+
+#[cfg(not(feature = "metrics"))]
+#[inline(always)]
+pub(crate) fn dns_query<T>(_result: &io::Result<T>) {}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn vlan_packet(direction: &'static str) {
+    metrics::counter!(VLAN_PACKETS, "direction" => direction).increment(1);
+}
+
+#[cfg(not(feature = "metrics"))]
+#[inline(always)]
+pub(crate) fn vlan_packet(_direction: &'static str) {}
@@ -1,82 +1,732 @@
-use crate::{Node, Nodes, BROADCAST_INTERVAL, MAX_SILENT_INTERVALS};
-use if_addrs::get_if_addrs;
+use crate::health::{run_health_checks, HealthCheck};
+use crate::net::{self, missing_interface};
+#[cfg(feature = "serde")]
+use crate::persist::{run_persister, Persistence};
+use crate::wire::{self, Announcement, AuthError, Kind};
+use crate::{
+    run_reaper, stats, DiscoveryError, Nodes, Tasks, BROADCAST_INTERVAL, MAX_SILENT_INTERVALS,
+};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::sync::Arc;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::net::UdpSocket;
-use tokio::sync::{broadcast, oneshot, watch};
-use tokio::time::sleep;
+use tokio::sync::{broadcast, oneshot, watch, Notify};
+use tokio::time::{sleep, timeout, timeout_at};
 use tokio::time::{Duration, Instant};
 use tracing::{debug, error, info, warn};
 
+/// Minimum gap between announcements forced with [`VlanHandle::announce_now`].
+const FORCED_ANNOUNCE_GAP: Duration = Duration::from_secs(1);
+const GOODBYE_REPEATS: usize = 3;
+const GOODBYE_SPACING: Duration = Duration::from_millis(50);
+/// How often throttled sources are summarised in the log.
+const THROTTLE_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Called with the sender and the reason for every datagram the receive task
+/// drops.
+pub type RejectCallback = Arc<dyn Fn(SocketAddr, RejectReason) + Send + Sync>;
+
+/// Optional knobs for [`discover`]; `VlanConfig::default()` keeps the stock
+/// behaviour.
+pub struct VlanConfig {
+    /// Discover peers but don't announce ourselves until
+    /// [`VlanHandle::promote`] is called.
+    pub standby: bool,
+    /// How long to wait at startup for our first broadcast to echo back (or
+    /// for any peer to announce) before deciding broadcast looks filtered.
+    /// `None` skips the probe, e.g. where the segment is known to deliver.
+    pub probe_timeout: Option<Duration>,
+    /// What to do when nothing comes back within `probe_timeout`.
+    pub on_probe_failure: ProbeFailurePolicy,
+    /// IP TTL (and multicast hop limit) for announcements. `None` leaves the
+    /// OS default, which for multicast is already 1 and stays on-segment.
+    pub ttl: Option<u32>,
+    /// Peers inserted before discovery starts so the table isn't empty until
+    /// the first announcements arrive.
+    pub seed_nodes: Vec<(Ipv4Addr, Option<String>, Option<u32>)>,
+    /// An already-bound socket to discover over instead of binding
+    /// `0.0.0.0:<broadcast_port>`, e.g. one handed over by systemd socket
+    /// activation or configured with custom options.
+    pub socket: Option<std::net::UdpSocket>,
+    /// Broadcast a goodbye on shutdown so peers drop us straight away instead
+    /// of waiting out the silence timeout.
+    pub announce_leaving: bool,
+    /// Announcements needed within one silence window before a new peer is
+    /// reported. 1 reports on first sight.
+    pub confirmation_threshold: u32,
+    /// Listen this long before first announcing, to catch another host
+    /// already announcing one of our addresses. `None` skips the check.
+    pub duplicate_check: Option<Duration>,
+    pub on_duplicate: DuplicatePolicy,
+    /// Announce on wall-clock multiples of the broadcast interval (e.g. :00,
+    /// :05, :10) instead of every interval from whenever we started, so
+    /// announcements line up across hosts in logs and captures.
+    pub align_to_wall_clock: bool,
+    pub on_rejected: Option<RejectCallback>,
+    /// Interfaces whose addresses we announce first, most preferred first.
+    /// Addresses on other interfaces follow, ordered by interface name.
+    pub preferred_interfaces: Vec<String>,
+    /// Announce from this address rather than the first private one found.
+    /// Without it, finding no private address is an error; set it to
+    /// 127.0.0.1 to discover on loopback, e.g. in CI.
+    pub own_ip: Option<Ipv4Addr>,
+    /// Stop the whole session if either the broadcast or the receive task
+    /// fails, instead of leaving the other running on its own, so a
+    /// supervisor can restart it cleanly. The failure is reported by
+    /// [`VlanHandle::join`].
+    pub supervise: bool,
+    /// Networks we discover on and accept announcements from. Empty means
+    /// every RFC 1918 range (10/8, 172.16/12 and 192.168/16).
+    pub allowed_networks: Vec<Ipv4Cidr>,
+    /// Time between announcements.
+    pub broadcast_interval: Duration,
+    /// Intervals a peer may miss before it is reaped.
+    pub max_silent_intervals: u32,
+    /// How often silent peers are looked for. `None` checks once per
+    /// `broadcast_interval`.
+    pub reap_interval: Option<Duration>,
+    /// Tag sent with our announcements, at most [`wire::MAX_TAG_LEN`] bytes.
+    pub tag: Option<String>,
+    /// Seq sent with our announcements.
+    pub seq: Option<u32>,
+    /// Shared secret for signing our announcements and verifying everyone
+    /// else's. Unsigned or wrongly signed datagrams are dropped, so every
+    /// peer on the segment needs the same secret. `None` trusts any private
+    /// sender.
+    pub secret: Option<Vec<u8>>,
+    /// How far a signed announcement's timestamp may be from our clock.
+    pub max_clock_skew: Duration,
+    pub transport: VlanTransport,
+    /// Datagrams accepted from any one source address. Excess datagrams are
+    /// dropped before they reach the node table, so a looping or malicious
+    /// host can't starve everyone else. `None` accepts everything.
+    pub rate_limit: Option<RateLimit>,
+    /// Probe every peer heard and mark those that don't answer unhealthy.
+    pub health_check: Option<HealthCheck>,
+    /// Restore the nodes saved here at startup and keep saving them, so a
+    /// restart begins with the last-known peers rather than none.
+    #[cfg(feature = "serde")]
+    pub persist: Option<Persistence>,
+}
+
+impl fmt::Debug for VlanConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_struct("VlanConfig");
+        s.field("standby", &self.standby)
+            .field("probe_timeout", &self.probe_timeout)
+            .field("on_probe_failure", &self.on_probe_failure)
+            .field("ttl", &self.ttl)
+            .field("seed_nodes", &self.seed_nodes)
+            .field("socket", &self.socket)
+            .field("announce_leaving", &self.announce_leaving)
+            .field("confirmation_threshold", &self.confirmation_threshold)
+            .field("duplicate_check", &self.duplicate_check)
+            .field("on_duplicate", &self.on_duplicate)
+            .field("align_to_wall_clock", &self.align_to_wall_clock)
+            .field("on_rejected", &self.on_rejected.is_some())
+            .field("preferred_interfaces", &self.preferred_interfaces)
+            .field("own_ip", &self.own_ip)
+            .field("supervise", &self.supervise)
+            .field("allowed_networks", &self.allowed_networks)
+            .field("broadcast_interval", &self.broadcast_interval)
+            .field("max_silent_intervals", &self.max_silent_intervals)
+            .field("reap_interval", &self.reap_interval)
+            .field("tag", &self.tag)
+            .field("seq", &self.seq)
+            .field("secret", &self.secret.is_some())
+            .field("max_clock_skew", &self.max_clock_skew)
+            .field("transport", &self.transport)
+            .field("rate_limit", &self.rate_limit)
+            .field("health_check", &self.health_check);
+        #[cfg(feature = "serde")]
+        s.field("persist", &self.persist);
+        s.finish()
+    }
+}
+
+/// An IPv4 network, e.g. `192.168.1.0/24`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Cidr {
+    addr: Ipv4Addr,
+    prefix_len: u8,
+}
+
+impl Ipv4Cidr {
+    pub fn new(addr: Ipv4Addr, prefix_len: u8) -> Option<Self> {
+        (prefix_len <= 32).then_some(Ipv4Cidr { addr, prefix_len })
+    }
+
+    pub fn contains(&self, ip: &Ipv4Addr) -> bool {
+        let mask = u32::MAX
+            .checked_shl(32 - u32::from(self.prefix_len))
+            .unwrap_or(0);
+        u32::from(*ip) & mask == u32::from(self.addr) & mask
+    }
+}
+
+impl FromStr for Ipv4Cidr {
+    type Err = DiscoveryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || DiscoveryError::InvalidConfig(format!("invalid IPv4 CIDR {:?}", s));
+        let (addr, prefix_len) = s.split_once('/').ok_or_else(invalid)?;
+        let addr = addr.parse().map_err(|_| invalid())?;
+        let prefix_len = prefix_len.parse().map_err(|_| invalid())?;
+        Ipv4Cidr::new(addr, prefix_len).ok_or_else(invalid)
+    }
+}
+
+/// Why the receive task dropped a datagram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RejectReason {
+    /// Sent from outside the allowed private networks.
+    NonPrivateSource,
+    /// Our own broadcast looped back.
+    OwnAddress,
+    /// An announcement from a peer that said goodbye moments ago.
+    Departed,
+    /// Unsigned, or not signed with our secret.
+    BadSignature,
+    /// Signed, but with a timestamp outside
+    /// [`max_clock_skew`](VlanConfig::max_clock_skew).
+    ClockSkew,
+    /// Over the source's [`rate_limit`](VlanConfig::rate_limit).
+    RateLimited,
+    /// Neither an announcement nor a legacy 4-byte address.
+    Malformed,
+}
+
+impl RejectReason {
+    const COUNT: usize = 7;
+
+    fn index(self) -> usize {
+        match self {
+            RejectReason::NonPrivateSource => 0,
+            RejectReason::OwnAddress => 1,
+            RejectReason::Departed => 2,
+            RejectReason::BadSignature => 3,
+            RejectReason::ClockSkew => 4,
+            RejectReason::RateLimited => 5,
+            RejectReason::Malformed => 6,
+        }
+    }
+}
+
+#[derive(Default)]
+struct RejectCounters([AtomicU64; RejectReason::COUNT]);
+
+impl RejectCounters {
+    fn record(&self, reason: RejectReason) {
+        self.0[reason.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get(&self, reason: RejectReason) -> u64 {
+        self.0[reason.index()].load(Ordering::Relaxed)
+    }
+}
+
+/// A token bucket per source address: each source may send `burst`
+/// datagrams at once, refilled at `per_second`. A peer announces once per
+/// broadcast interval and repeats its goodbye a few times, far inside the
+/// default of 10 a second with bursts of 20.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            per_second: 10.0,
+            burst: 20,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+    /// Datagrams dropped since the last report.
+    dropped: u64,
+}
+
+/// The buckets behind a [`RateLimit`], and the throttling log.
+struct RateLimiter {
+    limit: RateLimit,
+    buckets: HashMap<IpAddr, Bucket>,
+    last_report: Instant,
+}
+
+impl RateLimiter {
+    fn new(limit: RateLimit) -> Self {
+        RateLimiter {
+            limit,
+            buckets: HashMap::new(),
+            last_report: Instant::now(),
+        }
+    }
+
+    /// Takes a token for `src`, returning false if it has none left.
+    fn allow(&mut self, src: IpAddr, now: Instant) -> bool {
+        let burst = f64::from(self.limit.burst.max(1));
+        let bucket = self.buckets.entry(src).or_insert(Bucket {
+            tokens: burst,
+            refilled: now,
+            dropped: 0,
+        });
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.limit.per_second).min(burst);
+        bucket.refilled = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            bucket.dropped += 1;
+            false
+        }
+    }
+
+    /// Logs one line per source throttled since the last report, at most
+    /// once per [`THROTTLE_REPORT_INTERVAL`], rather than one per datagram.
+    /// Forgets sources whose buckets have refilled, so spoofed addresses
+    /// don't pile up.
+    fn report(&mut self, now: Instant) {
+        let since = now.duration_since(self.last_report);
+        if since < THROTTLE_REPORT_INTERVAL {
+            return;
+        }
+        self.last_report = now;
+        let burst = f64::from(self.limit.burst.max(1));
+        let per_second = self.limit.per_second;
+        self.buckets.retain(|src, bucket| {
+            if bucket.dropped > 0 {
+                warn!(
+                    "Throttled {}: dropped {} datagrams over {:?}, above {} a second",
+                    src, bucket.dropped, since, per_second
+                );
+                bucket.dropped = 0;
+            }
+            let idle = now.duration_since(bucket.refilled).as_secs_f64();
+            bucket.tokens + idle * per_second < burst
+        });
+    }
+}
+
+/// What to do when the startup duplicate check finds our address in use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Fail `discover` with [`DiscoveryError::DuplicateAddress`].
+    Refuse,
+    /// Log an error and carry on.
+    Warn,
+}
+
+/// What to do when the startup probe hears nothing back, as on networks that
+/// accept broadcasts without delivering them, such as most cloud VPCs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeFailurePolicy {
+    /// Fail `discover` with [`DiscoveryError::ProbeFailed`].
+    Refuse,
+    /// Log a warning and carry on, in case peers can still be heard.
+    Warn,
+}
+
+/// How announcements reach the rest of the segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VlanTransport {
+    /// Directed broadcast to our subnet's `.255` address.
+    Broadcast,
+    /// Send to, and listen on, a multicast group such as `239.255.0.1`. Many
+    /// managed switches and virtual networks drop directed broadcast but
+    /// pass multicast.
+    Multicast(Ipv4Addr),
+}
+
+impl Default for VlanConfig {
+    fn default() -> Self {
+        VlanConfig {
+            standby: false,
+            probe_timeout: Some(Duration::from_secs(1)),
+            on_probe_failure: ProbeFailurePolicy::Refuse,
+            ttl: None,
+            seed_nodes: Vec::new(),
+            socket: None,
+            announce_leaving: true,
+            confirmation_threshold: 1,
+            duplicate_check: None,
+            on_duplicate: DuplicatePolicy::Refuse,
+            align_to_wall_clock: false,
+            on_rejected: None,
+            preferred_interfaces: Vec::new(),
+            own_ip: None,
+            supervise: false,
+            allowed_networks: Vec::new(),
+            broadcast_interval: BROADCAST_INTERVAL,
+            max_silent_intervals: MAX_SILENT_INTERVALS as u32,
+            reap_interval: None,
+            tag: None,
+            seq: None,
+            secret: None,
+            max_clock_skew: Duration::from_secs(30),
+            transport: VlanTransport::Broadcast,
+            rate_limit: Some(RateLimit::default()),
+            health_check: None,
+            #[cfg(feature = "serde")]
+            persist: None,
+        }
+    }
+}
+
+/// A running VLAN discovery session.
+pub struct VlanHandle {
+    pub up: oneshot::Receiver<()>,
+    pub fin: oneshot::Receiver<()>,
+    pub shutdown: watch::Sender<()>,
+    pub nodes: Arc<Nodes>,
+    announcing: watch::Sender<bool>,
+    force_announce: Arc<Notify>,
+    last_forced: Mutex<Option<Instant>>,
+    rejected: Arc<RejectCounters>,
+    config: VlanConfig,
+    tasks: Tasks,
+}
+
+impl VlanHandle {
+    /// Stops announcing this node while continuing to discover peers.
+    pub fn pause(&self) {
+        let _ = self.announcing.send(false);
+    }
+
+    /// Resumes announcing after [`pause`](Self::pause).
+    pub fn resume(&self) {
+        let _ = self.announcing.send(true);
+    }
+
+    /// Starts announcing a node that was started in standby.
+    pub fn promote(&self) {
+        self.resume();
+    }
+
+    pub fn is_announcing(&self) -> bool {
+        *self.announcing.borrow()
+    }
+
+    /// Announces immediately instead of at the next interval, e.g. right
+    /// after resuming. Limited to one forced announcement per second; returns
+    /// false if this call was swallowed by that limit.
+    pub fn announce_now(&self) -> bool {
+        let mut last_forced = self.last_forced.lock().unwrap();
+        let now = Instant::now();
+        if matches!(*last_forced, Some(at) if now.duration_since(at) < FORCED_ANNOUNCE_GAP) {
+            return false;
+        }
+        *last_forced = Some(now);
+        self.force_announce.notify_one();
+        true
+    }
+
+    /// The configuration the session is running with, minimums applied. An
+    /// adopted `socket` has been handed to the session and shows as `None`.
+    pub fn config(&self) -> &VlanConfig {
+        &self.config
+    }
+
+    /// Datagrams dropped for `reason` since discovery started.
+    pub fn rejected(&self, reason: RejectReason) -> u64 {
+        self.rejected.get(reason)
+    }
+
+    /// False once the broadcast or receive task has exited, whether through
+    /// shutdown or a panic.
+    pub fn is_alive(&self) -> bool {
+        self.tasks.is_alive()
+    }
+
+    /// Waits for the discovery tasks to exit, surfacing a panic in either as
+    /// [`DiscoveryError::TaskFailed`].
+    pub async fn join(&mut self) -> Result<(), DiscoveryError> {
+        self.tasks.join().await
+    }
+}
+
 pub async fn discover(
     broadcast_port: u16,
-) -> Result<
-    (
-        oneshot::Receiver<()>,
-        oneshot::Receiver<()>,
-        watch::Sender<()>,
-        Arc<Nodes>,
-    ),
-    Box<dyn std::error::Error + Send + Sync>,
-> {
-    let nodes = Arc::new(Nodes::new());
+    config: VlanConfig,
+) -> Result<VlanHandle, DiscoveryError> {
+    let nodes = Arc::new(nodes_for(&config));
+    start(broadcast_port, config, nodes, false).await
+}
+
+/// The node table a session configured by `config` works with.
+pub(crate) fn nodes_for(config: &VlanConfig) -> Nodes {
+    Nodes::new()
+        .with_confirmation_threshold(config.confirmation_threshold.max(1))
+        .with_silence_window(config.broadcast_interval * config.max_silent_intervals)
+}
+
+/// Runs a session over `nodes`, which may be shared with another discovery
+/// source. With `defer_metadata`, announcements only fill in a tag or seq a
+/// node doesn't already have.
+pub(crate) async fn start(
+    broadcast_port: u16,
+    mut config: VlanConfig,
+    nodes: Arc<Nodes>,
+    defer_metadata: bool,
+) -> Result<VlanHandle, DiscoveryError> {
+    config.confirmation_threshold = config.confirmation_threshold.max(1);
+    if let Some(tag) = config
+        .tag
+        .as_deref()
+        .filter(|t| t.len() > wire::MAX_TAG_LEN)
+    {
+        return Err(DiscoveryError::InvalidConfig(format!(
+            "tag is {} bytes, longer than the {} that fit in an announcement",
+            tag.len(),
+            wire::MAX_TAG_LEN
+        )));
+    }
+    if let VlanTransport::Multicast(group) = config.transport {
+        if !group.is_multicast() {
+            return Err(DiscoveryError::InvalidConfig(format!(
+                "{} is not a multicast group address",
+                group
+            )));
+        }
+    }
+    info!("VLAN discovery config: {:?}", config);
+    let broadcast_interval = config.broadcast_interval;
 
     let (shutdown_tx, mut shutdown_rx) = watch::channel(());
+    let (announcing_tx, announcing_rx) = watch::channel(!config.standby);
     let (up_tx, up_rx) = oneshot::channel();
     let (fin_tx, fin_rx) = oneshot::channel();
 
-    let own_ip = get_own_private_ip().unwrap_or(Ipv4Addr::new(127, 0, 0, 1));
-    info!("Own IP address: {}", own_ip);
+    if let Some(name) = missing_interface(&config.preferred_interfaces) {
+        return Err(DiscoveryError::InvalidInterface(name));
+    }
+    let mut own_ips =
+        get_own_private_ips_preferring(&config.preferred_interfaces, &config.allowed_networks);
+    match config.own_ip {
+        Some(ip) => {
+            if ip.is_loopback() {
+                warn!(
+                    "Own IP {} is loopback; only peers on this host will hear us",
+                    ip
+                );
+            }
+            own_ips.retain(|other| *other != ip);
+            own_ips.insert(0, ip);
+        }
+        None if own_ips.is_empty() => {
+            // announcing from loopback reaches nobody, so don't guess
+            warn!(
+                "No private IPv4 address found and no own_ip configured; \
+                 not falling back to loopback"
+            );
+            return Err(DiscoveryError::NoPrivateIp);
+        }
+        None => {}
+    }
+    let own_ip = own_ips[0];
+    info!(
+        "Own IP address: {} ({})",
+        own_ip,
+        match config.own_ip {
+            Some(_) => "configured",
+            None => "first private address",
+        }
+    );
+    for ip in &own_ips {
+        nodes.add_own_ip(*ip);
+    }
+    #[cfg(feature = "serde")]
+    if let Some(persist) = &config.persist {
+        nodes.restore(&persist.path);
+    }
+    for (ip, tag, seq) in &config.seed_nodes {
+        nodes.insert_unchecked(*ip, tag.clone(), *seq);
+    }
 
-    let socket = Arc::new(
-        UdpSocket::bind(("0.0.0.0", broadcast_port))
+    let goodbye = Announcement::new(Kind::Goodbye, own_ips.clone()).encode();
+    let payload = Announcement {
+        tag: config.tag.clone(),
+        seq: config.seq,
+        ..Announcement::new(Kind::Announce, own_ips)
+    }
+    .encode();
+    let announce_leaving = config.announce_leaving;
+    let align_to_wall_clock = config.align_to_wall_clock;
+    let secret = config.secret.clone();
+
+    let socket = match config.socket.take() {
+        Some(socket) => {
+            socket.set_nonblocking(true).map_err(DiscoveryError::Bind)?;
+            UdpSocket::from_std(socket).map_err(DiscoveryError::Bind)?
+        }
+        None => UdpSocket::bind(("0.0.0.0", broadcast_port))
             .await
-            .expect("Failed to bind socket"),
-    );
-    socket.set_broadcast(true).expect("Failed to set broadcast");
+            .map_err(DiscoveryError::Bind)?,
+    };
+    // announcements go to an IPv4 broadcast address, which an IPv6 socket
+    // can never reach
+    let local_addr = socket.local_addr().map_err(DiscoveryError::Bind)?;
+    if local_addr.is_ipv6() {
+        return Err(DiscoveryError::InvalidConfig(format!(
+            "VLAN discovery broadcasts over IPv4 but the socket is bound to {}",
+            local_addr
+        )));
+    }
+    let socket = Arc::new(socket);
+    match config.transport {
+        VlanTransport::Broadcast => {
+            socket.set_broadcast(true).map_err(DiscoveryError::Bind)?;
+        }
+        VlanTransport::Multicast(group) => {
+            // join on the interface we announce from; a loopback own_ip,
+            // configured explicitly, leaves the choice to the kernel
+            let interface = match own_ip.is_loopback() {
+                true => Ipv4Addr::UNSPECIFIED,
+                false => own_ip,
+            };
+            socket
+                .join_multicast_v4(group, interface)
+                .map_err(DiscoveryError::Bind)?;
+            // hear our own announcements, as with broadcast, so the probe
+            // and the loopback checks behave the same
+            socket
+                .set_multicast_loop_v4(true)
+                .map_err(DiscoveryError::Bind)?;
+        }
+    }
+    if let Some(ttl) = config.ttl {
+        socket.set_ttl(ttl).map_err(DiscoveryError::Bind)?;
+        socket
+            .set_multicast_ttl_v4(ttl)
+            .map_err(DiscoveryError::Bind)?;
+    }
 
-    let ip_str = own_ip.to_string();
-    let octets: Vec<&str> = ip_str.split('.').collect();
+    let broadcast_ip = target_ip(config.transport, own_ip).to_string();
 
-    if octets.len() != 4 {
-        return Err("Invalid IP address format".into());
+    if let Some(wait) = config.duplicate_check {
+        if let Some(ip) = find_duplicate(&socket, &own_ips_set(&nodes), wait).await {
+            error!("Another host is already announcing {}", ip);
+            if config.on_duplicate == DuplicatePolicy::Refuse {
+                return Err(DiscoveryError::DuplicateAddress(ip));
+            }
+        }
     }
 
-    let broadcast_ip = format!("{}.{}.{}.255", octets[0], octets[1], octets[2]);
+    if let Some(wait) = config.probe_timeout.filter(|_| !config.standby) {
+        let target = (broadcast_ip.as_str(), broadcast_port);
+        let probe = sign(&payload, secret.as_deref());
+        if !probe_broadcast(&socket, &probe, target, wait).await {
+            warn!(
+                "Nothing heard within {:?} of announcing to {}:{}; {:?} may be filtered on this segment",
+                wait, broadcast_ip, broadcast_port, config.transport
+            );
+            if config.on_probe_failure == ProbeFailurePolicy::Refuse {
+                return Err(DiscoveryError::ProbeFailed(format!(
+                    "{}:{}",
+                    broadcast_ip, broadcast_port
+                )));
+            }
+        }
+    }
 
     let _ = up_tx.send(());
 
+    let force_announce = Arc::new(Notify::new());
+    let force_announce_clone = Arc::clone(&force_announce);
     let nodes_clone = Arc::clone(&nodes);
     let socket_clone = Arc::clone(&socket);
     let mut shutdown_clone = shutdown_rx.clone();
+    let fin = Arc::new(FinGuard(Some(fin_tx)));
+    let fin_clone = Arc::clone(&fin);
+    let mut tasks = Tasks::default();
     // Task for broadcasting
-    tokio::spawn(async move {
+    tasks.spawn(async move {
+        let _fin = fin_clone;
         loop {
             tokio::select! {
                 _ = shutdown_clone.changed() => {
                     info!("Shutdown signal received, stopping broadcast task");
+                    let announcing = *announcing_rx.borrow();
+                    if announce_leaving && announcing {
+                        let target = (broadcast_ip.as_str(), broadcast_port);
+                        let goodbye = sign(&goodbye, secret.as_deref());
+                        send_goodbye(&socket_clone, &goodbye, target).await;
+                    }
                     break;
                 }
-                _ = sleep(BROADCAST_INTERVAL) => {
-                    nodes_clone.reap();
-                    match socket_clone
-                        .send_to(&own_ip.octets(), (broadcast_ip.as_str(), broadcast_port))
-                        .await
-                    {
-                        Ok(_) => {}
-                        Err(e) => {
-                            error!("Failed to send broadcast: {}", e);
-                        }
+                _ = force_announce_clone.notified() => {
+                    if !*announcing_rx.borrow() {
+                        continue;
+                    }
+                    let payload = sign(&payload, secret.as_deref());
+                    announce(&socket_clone, &payload, (broadcast_ip.as_str(), broadcast_port)).await;
+                }
+                _ = sleep(next_broadcast_in(broadcast_interval, align_to_wall_clock)) => {
+                    if !*announcing_rx.borrow() {
+                        continue;
                     }
+                    let payload = sign(&payload, secret.as_deref());
+                    announce(&socket_clone, &payload, (broadcast_ip.as_str(), broadcast_port)).await;
                 }
             }
         }
     });
 
+    let reap_interval = config.reap_interval.unwrap_or(broadcast_interval);
+    tasks.spawn(run_reaper(
+        Arc::clone(&nodes),
+        reap_interval,
+        shutdown_rx.clone(),
+    ));
+    if let Some(check) = config.health_check.clone() {
+        tasks.spawn(run_health_checks(
+            Arc::clone(&nodes),
+            check,
+            shutdown_rx.clone(),
+        ));
+    }
+    #[cfg(feature = "serde")]
+    if let Some(persist) = config.persist.clone() {
+        tasks.spawn(run_persister(
+            Arc::clone(&nodes),
+            persist,
+            shutdown_rx.clone(),
+        ));
+    }
+
     let nodes_clone = Arc::clone(&nodes);
+    let rejected = Arc::new(RejectCounters::default());
+    let rejected_clone = Arc::clone(&rejected);
+    let on_rejected = config.on_rejected.clone();
+    let allowed_networks = config.allowed_networks.clone();
+    let rate_limit = config.rate_limit;
+    let auth = config.secret.clone().map(|secret| Auth {
+        secret,
+        max_skew: config.max_clock_skew,
+    });
 
     // Task for receiving
-    tokio::spawn(async move {
+    tasks.spawn(async move {
+        let _fin = fin;
+        let mut listener = Listener::new(
+            nodes_clone,
+            allowed_networks,
+            broadcast_interval,
+            rejected_clone,
+            on_rejected,
+        )
+        .with_auth(auth)
+        .with_rate_limit(rate_limit)
+        .with_deferred_metadata(defer_metadata);
         let mut buffer = [0; 1024];
         loop {
             tokio::select! {
@@ -86,19 +736,9 @@ pub async fn discover(
                 }
                 result = socket.recv_from(&mut buffer) => {
                     match result {
-                        Ok((_, src_addr)) => {
-                            if let Some(discovered_ip) = extract_private_ip(&src_addr) {
-                                if discovered_ip != own_ip {
-                                    if !nodes_clone.test(&discovered_ip) {
-                                        info!("Discovered new node: {}", discovered_ip);
-                                    }
-                                    // always add nodes to refresh last_seen
-                                    let is_self = own_ip == discovered_ip;
-                                    nodes_clone.add(discovered_ip, None, None, is_self);
-                                };
-                            } else {
-                                warn!("Received broadcast from non-private IP: {}", src_addr.ip());
-                            }
+                        Ok((len, src_addr)) => {
+                            stats::vlan_packet("received");
+                            listener.handle(&buffer[..len], &src_addr);
                         }
                         Err(e) => {
                             warn!("Error receiving broadcast: {}", e);
@@ -109,33 +749,369 @@ pub async fn discover(
         }
     });
 
-    Ok((up_rx, fin_rx, shutdown_tx, Arc::clone(&nodes)))
+    if config.supervise {
+        tasks.supervise();
+    }
+
+    Ok(VlanHandle {
+        up: up_rx,
+        fin: fin_rx,
+        shutdown: shutdown_tx,
+        nodes: Arc::clone(&nodes),
+        announcing: announcing_tx,
+        force_announce,
+        last_forced: Mutex::new(None),
+        rejected,
+        config,
+        tasks,
+    })
 }
 
-pub fn get_own_private_ip() -> Option<Ipv4Addr> {
-    let addrs = match get_if_addrs() {
-        Ok(addrs) => addrs,
-        Err(e) => {
-            warn!("Failed to get network interfaces: {}", e);
-            return None;
+/// Held by both discovery tasks; fires `fin` once the last of them has
+/// exited, however it exited.
+struct FinGuard(Option<oneshot::Sender<()>>);
+
+impl Drop for FinGuard {
+    fn drop(&mut self) {
+        if let Some(fin) = self.0.take() {
+            let _ = fin.send(());
         }
-    };
+    }
+}
 
-    for addr in addrs {
-        if let IpAddr::V4(ip) = addr.ip() {
-            if ip.is_private() && ip.octets()[0] == 10 {
-                return Some(ip);
+/// Processes datagrams for the receive task.
+struct Listener {
+    nodes: Arc<Nodes>,
+    allowed_networks: Vec<Ipv4Cidr>,
+    /// When each peer said goodbye, so a straggling announcement sent just
+    /// before it doesn't bring the peer back.
+    departed: HashMap<Ipv4Addr, Instant>,
+    /// How long a goodbye shuts out a peer's announcements: one broadcast
+    /// interval, enough for any sent just before it to arrive.
+    tombstone_window: Duration,
+    rejected: Arc<RejectCounters>,
+    on_rejected: Option<RejectCallback>,
+    auth: Option<Auth>,
+    limiter: Option<RateLimiter>,
+    /// Only fill in a tag or seq the node doesn't have yet, leaving those
+    /// another discovery source set alone.
+    defer_metadata: bool,
+}
+
+/// The shared secret datagrams must be signed with.
+struct Auth {
+    secret: Vec<u8>,
+    max_skew: Duration,
+}
+
+impl Listener {
+    fn new(
+        nodes: Arc<Nodes>,
+        allowed_networks: Vec<Ipv4Cidr>,
+        tombstone_window: Duration,
+        rejected: Arc<RejectCounters>,
+        on_rejected: Option<RejectCallback>,
+    ) -> Self {
+        Listener {
+            nodes,
+            allowed_networks,
+            departed: HashMap::new(),
+            tombstone_window,
+            rejected,
+            on_rejected,
+            auth: None,
+            limiter: None,
+            defer_metadata: false,
+        }
+    }
+
+    fn with_auth(mut self, auth: Option<Auth>) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    fn with_rate_limit(mut self, limit: Option<RateLimit>) -> Self {
+        self.limiter = limit.map(RateLimiter::new);
+        self
+    }
+
+    fn with_deferred_metadata(mut self, defer: bool) -> Self {
+        self.defer_metadata = defer;
+        self
+    }
+
+    fn reject(&self, src_addr: &SocketAddr, reason: RejectReason) {
+        self.rejected.record(reason);
+        if let Some(on_rejected) = &self.on_rejected {
+            on_rejected(*src_addr, reason);
+        }
+    }
+
+    /// Our own broadcasts, goodbyes included, can loop back to us, and on a
+    /// multi-address host they may arrive from any of its addresses, so the
+    /// source and every announced address are checked against the whole
+    /// own-IP set in `nodes`, including addresses another discovery source
+    /// registered after we started.
+    fn is_own(&self, src: &Ipv4Addr, announcement: &Announcement) -> bool {
+        self.nodes.is_own_ip(src) || announcement.addrs.iter().any(|ip| self.nodes.is_own_ip(ip))
+    }
+
+    fn handle(&mut self, buf: &[u8], src_addr: &SocketAddr) {
+        // before anything that logs or locks, so a flood costs next to nothing
+        if let Some(limiter) = &mut self.limiter {
+            let now = Instant::now();
+            let allowed = limiter.allow(src_addr.ip(), now);
+            limiter.report(now);
+            if !allowed {
+                self.reject(src_addr, RejectReason::RateLimited);
+                return;
             }
         }
+        let Some(discovered_ip) = extract_private_ip(src_addr, &self.allowed_networks) else {
+            warn!("Received broadcast from non-private IP: {}", src_addr.ip());
+            self.reject(src_addr, RejectReason::NonPrivateSource);
+            return;
+        };
+        let buf = match &self.auth {
+            Some(auth) => match wire::open(buf, &auth.secret, SystemTime::now(), auth.max_skew) {
+                Ok(payload) => payload,
+                Err(AuthError::BadSignature) => {
+                    warn!("Dropping unauthenticated broadcast from {}", src_addr);
+                    self.reject(src_addr, RejectReason::BadSignature);
+                    return;
+                }
+                Err(AuthError::Skew) => {
+                    warn!(
+                        "Dropping broadcast from {} outside the {:?} clock skew window",
+                        src_addr, auth.max_skew
+                    );
+                    self.reject(src_addr, RejectReason::ClockSkew);
+                    return;
+                }
+            },
+            None => buf,
+        };
+        let Some(announcement) = Announcement::decode(buf) else {
+            debug!("Dropping undecodable datagram from {}", src_addr);
+            self.reject(src_addr, RejectReason::Malformed);
+            return;
+        };
+        // a signature only vouches for the addresses inside it, so a replay
+        // from another host mustn't get that host added
+        if self.auth.is_some() && !announcement.addrs.contains(&discovered_ip) {
+            warn!(
+                "Dropping signed broadcast from {} that doesn't announce it",
+                src_addr
+            );
+            self.reject(src_addr, RejectReason::BadSignature);
+            return;
+        }
+        if self.is_own(&discovered_ip, &announcement) {
+            self.reject(src_addr, RejectReason::OwnAddress);
+            return;
+        }
+
+        let now = Instant::now();
+        self.departed
+            .retain(|_, at| now.duration_since(*at) < self.tombstone_window);
+
+        if announcement.kind == Kind::Goodbye {
+            // goodbyes are repeated, so only the first one finds the node
+            if self.nodes.depart(&discovered_ip) {
+                info!("Node left: {}", discovered_ip);
+            }
+            self.departed.insert(discovered_ip, now);
+            return;
+        }
+        if self.departed.contains_key(&discovered_ip) {
+            debug!("Ignoring announcement from departed node {}", discovered_ip);
+            self.reject(src_addr, RejectReason::Departed);
+            return;
+        }
+
+        if !self.nodes.contains(&discovered_ip) {
+            info!("Discovered new node: {}", discovered_ip);
+        }
+        // key the node by the address we heard it on, and keep whatever else
+        // it announced alongside
+        let mut addrs = vec![discovered_ip];
+        addrs.extend(
+            announcement
+                .addrs
+                .into_iter()
+                .filter(|ip| *ip != discovered_ip),
+        );
+        let (mut tag, mut seq) = (announcement.tag, announcement.seq);
+        if self.defer_metadata {
+            if let Some(known) = self.nodes.get(&discovered_ip) {
+                tag = tag.filter(|_| known.tag().is_none());
+                seq = seq.filter(|_| known.seq().is_none());
+            }
+        }
+        // always add nodes to refresh last_seen
+        self.nodes.add_multihomed(addrs, tag, seq);
+    }
+}
+
+fn next_broadcast_in(interval: Duration, align_to_wall_clock: bool) -> Duration {
+    if !align_to_wall_clock {
+        return interval;
+    }
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => until_boundary(since_epoch, interval),
+        Err(_) => interval,
     }
+}
+
+/// Time from `since_epoch` to the next whole multiple of `interval`. Exactly
+/// on a boundary waits a full interval, so we never announce twice at once.
+fn until_boundary(since_epoch: Duration, interval: Duration) -> Duration {
+    let interval_nanos = interval.as_nanos().max(1);
+    let into_interval = since_epoch.as_nanos() % interval_nanos;
+    Duration::from_nanos((interval_nanos - into_interval) as u64)
+}
 
+/// Signs `payload` with the shared secret, if there is one.
+fn sign(payload: &[u8], secret: Option<&[u8]>) -> Vec<u8> {
+    match secret {
+        Some(secret) => wire::seal(payload, secret, SystemTime::now()),
+        None => payload.to_vec(),
+    }
+}
+
+async fn announce(socket: &UdpSocket, payload: &[u8], target: (&str, u16)) {
+    match socket.send_to(payload, target).await {
+        Ok(_) => stats::vlan_packet("sent"),
+        Err(e) => error!("Failed to send broadcast: {}", e),
+    }
+}
+
+/// Sends the goodbye a few times, since a single lost datagram would leave
+/// peers waiting out the silence timeout after all.
+async fn send_goodbye(socket: &UdpSocket, goodbye: &[u8], target: (&str, u16)) {
+    for attempt in 0..GOODBYE_REPEATS {
+        if attempt > 0 {
+            sleep(GOODBYE_SPACING).await;
+        }
+        match socket.send_to(goodbye, target).await {
+            Ok(_) => stats::vlan_packet("sent"),
+            Err(e) => warn!("Failed to send goodbye: {}", e),
+        }
+    }
+}
+
+fn own_ips_set(nodes: &Nodes) -> HashSet<Ipv4Addr> {
+    let mut own_ips = nodes.own_ips();
+    own_ips.remove(&Ipv4Addr::LOCALHOST);
+    own_ips
+}
+
+/// Listens for `wait` before we've announced anything, so any datagram from
+/// or claiming one of our addresses must come from another host.
+async fn find_duplicate(
+    socket: &UdpSocket,
+    own_ips: &HashSet<Ipv4Addr>,
+    wait: Duration,
+) -> Option<Ipv4Addr> {
+    let deadline = Instant::now() + wait;
+    let mut buffer = [0; 1024];
+    while let Ok(Ok((len, src_addr))) = timeout_at(deadline, socket.recv_from(&mut buffer)).await {
+        if let IpAddr::V4(src) = src_addr.ip() {
+            if own_ips.contains(&src) {
+                return Some(src);
+            }
+        }
+        if let Some(announcement) = Announcement::decode(&buffer[..len]) {
+            if let Some(ip) = announcement.addrs.iter().find(|ip| own_ips.contains(ip)) {
+                return Some(*ip);
+            }
+        }
+    }
     None
 }
 
-fn extract_private_ip(addr: &SocketAddr) -> Option<Ipv4Addr> {
+/// Sends one announcement and waits up to `wait` for any datagram to arrive,
+/// either our own broadcast looped back or a peer's announcement.
+async fn probe_broadcast(
+    socket: &UdpSocket,
+    payload: &[u8],
+    target: (&str, u16),
+    wait: Duration,
+) -> bool {
+    if let Err(e) = socket.send_to(payload, target).await {
+        warn!("Failed to send broadcast probe: {}", e);
+        return false;
+    }
+
+    let mut buffer = [0; 1024];
+    matches!(
+        timeout(wait, socket.recv_from(&mut buffer)).await,
+        Ok(Ok(_))
+    )
+}
+
+pub fn get_own_private_ip() -> Option<Ipv4Addr> {
+    get_own_private_ips().into_iter().next()
+}
+
+/// All private addresses on this host, ordered by interface name and then
+/// address so the primary address doesn't depend on platform enumeration
+/// order.
+pub fn get_own_private_ips() -> Vec<Ipv4Addr> {
+    get_own_private_ips_preferring(&[], &[])
+}
+
+/// Like [`get_own_private_ips`], but addresses on the `preferred` interfaces
+/// come first, in the order listed, and only addresses within `networks`
+/// count if any are given.
+pub fn get_own_private_ips_preferring(
+    preferred: &[String],
+    networks: &[Ipv4Cidr],
+) -> Vec<Ipv4Addr> {
+    let mut candidates = Vec::new();
+    for addr in net::interfaces() {
+        if let IpAddr::V4(ip) = addr.ip() {
+            if is_allowed(&ip, networks) {
+                candidates.push((addr.name, ip));
+            }
+        }
+    }
+
+    prioritize(candidates, preferred)
+}
+
+fn prioritize(mut candidates: Vec<(String, Ipv4Addr)>, preferred: &[String]) -> Vec<Ipv4Addr> {
+    candidates.sort_by(|(a_name, a_ip), (b_name, b_ip)| {
+        let rank = |name: &String| {
+            preferred
+                .iter()
+                .position(|p| p == name)
+                .unwrap_or(preferred.len())
+        };
+        (rank(a_name), a_name, a_ip).cmp(&(rank(b_name), b_name, b_ip))
+    });
+
+    let mut ips = Vec::new();
+    for (_, ip) in candidates {
+        if !ips.contains(&ip) {
+            ips.push(ip);
+        }
+    }
+    ips
+}
+
+fn is_allowed(ip: &Ipv4Addr, networks: &[Ipv4Cidr]) -> bool {
+    if networks.is_empty() {
+        ip.is_private()
+    } else {
+        networks.iter().any(|network| network.contains(ip))
+    }
+}
+
+fn extract_private_ip(addr: &SocketAddr, networks: &[Ipv4Cidr]) -> Option<Ipv4Addr> {
     match addr.ip() {
         IpAddr::V4(ipv4) => {
-            if ipv4.is_private() && ipv4.octets()[0] == 10 {
+            if is_allowed(&ipv4, networks) {
                 Some(ipv4)
             } else {
                 None
@@ -145,50 +1121,521 @@ fn extract_private_ip(addr: &SocketAddr) -> Option<Ipv4Addr> {
     }
 }
 
+/// Where announcements from `own_ip` are sent.
+fn target_ip(transport: VlanTransport, own_ip: Ipv4Addr) -> Ipv4Addr {
+    match transport {
+        VlanTransport::Broadcast => subnet_broadcast(own_ip),
+        VlanTransport::Multicast(group) => group,
+    }
+}
+
+/// The broadcast address of `ip`'s subnet, assuming it is a /24. On a wider
+/// subnet this is an ordinary host address (so announcements only reach
+/// that host), and on a narrower one it only broadcasts if `ip` is in the
+/// top block; pass a bound `socket` and broadcast to a fixed address
+/// yourself if that doesn't fit.
+fn subnet_broadcast(ip: Ipv4Addr) -> Ipv4Addr {
+    let [a, b, c, _] = ip.octets();
+    Ipv4Addr::new(a, b, c, 255)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::Node;
     use std::str::FromStr;
     use std::thread::sleep;
 
     #[test]
     fn test_get_own_private_ip() {
-        let ip: Option<Ipv4Addr> = get_own_private_ip();
-        assert_eq!(ip, None);
+        if let Some(ip) = get_own_private_ip() {
+            assert!(ip.is_private());
+        }
     }
 
     #[test]
-    fn test_nodes_add_and_test() {
-        let nodes: Nodes = Nodes::new([]);
-        nodes.add(Ipv4Addr::from_str("127.0.0.1").unwrap());
-        assert!(nodes.test(Ipv4Addr::from_str("127.0.0.1").unwrap()));
-        assert!(!nodes.test(Ipv4Addr::from_str("192.168.0.1").unwrap()));
+    fn test_nodes_add_and_contains() {
+        let nodes = Nodes::new();
+        nodes.add(Ipv4Addr::from_str("127.0.0.1").unwrap(), None, None);
+        assert!(nodes.contains(&Ipv4Addr::from_str("127.0.0.1").unwrap()));
+        assert!(!nodes.contains(&Ipv4Addr::from_str("192.168.0.1").unwrap()));
     }
 
     #[test]
     fn test_nodes_all() {
-        let nodes: Nodes = Nodes::new([]);
-        nodes.add(Ipv4Addr::from_str("127.0.0.1").unwrap());
-        nodes.add(Ipv4Addr::from_str("192.168.0.1").unwrap());
+        let nodes = Nodes::new();
+        nodes.add(Ipv4Addr::from_str("127.0.0.1").unwrap(), None, None);
+        nodes.add(Ipv4Addr::from_str("192.168.0.1").unwrap(), None, None);
         let all_nodes: Vec<Node> = nodes.all();
         assert_eq!(all_nodes.len(), 2);
         assert!(all_nodes
             .iter()
-            .any(|node| node.ip == Ipv4Addr::from_str("127.0.0.1").unwrap()));
+            .any(|node| node.ip() == Ipv4Addr::from_str("127.0.0.1").unwrap()));
         assert!(all_nodes
             .iter()
-            .any(|node| node.ip == Ipv4Addr::from_str("192.168.0.1").unwrap()));
+            .any(|node| node.ip() == Ipv4Addr::from_str("192.168.0.1").unwrap()));
     }
 
     #[test]
     fn test_nodes_reap() {
-        let nodes: Nodes = Nodes::new([]);
-        nodes.add(Ipv4Addr::from_str("127.0.0.1").unwrap());
-        nodes.add(Ipv4Addr::from_str("192.168.0.1").unwrap());
-        sleep(Duration::from_secs(
-            (MAX_SILENT_INTERVALS + 1) * BROADCAST_INTERVAL.as_secs(),
-        ));
+        let nodes = Nodes::new().with_silence_window(Duration::from_millis(10));
+        nodes.add(Ipv4Addr::from_str("127.0.0.1").unwrap(), None, None);
+        nodes.add(Ipv4Addr::from_str("192.168.0.1").unwrap(), None, None);
+        sleep(Duration::from_millis(20));
         nodes.reap();
         assert_eq!(nodes.all().len(), 0);
     }
+
+    /// Every way a node reaches the table goes through the same `add`, and
+    /// `own_ips` decides which of them is us.
+    #[test]
+    fn test_unified_add() {
+        let nodes = Arc::new(Nodes::new());
+        let own = Ipv4Addr::new(10, 0, 0, 1);
+        nodes.add_own_ip(own);
+
+        // directly, as a library consumer or seed would
+        nodes.add(Ipv4Addr::new(10, 0, 0, 2), Some("uk-lon".into()), Some(1));
+        // as DNS discovery does, which may resolve our own name
+        nodes.add(own, Some("uk-lon".into()), Some(2));
+        // as the VLAN listener does
+        let mut listener = Listener::new(
+            Arc::clone(&nodes),
+            Vec::new(),
+            BROADCAST_INTERVAL,
+            Arc::new(RejectCounters::default()),
+            None,
+        );
+        listener.handle(&[10, 0, 0, 3], &SocketAddr::from(([10, 0, 0, 3], 9000)));
+
+        let all = nodes.ordered();
+        assert_eq!(all.len(), 3);
+        assert!(!all[1].is_self());
+        assert!(all[0].is_self());
+        assert!(!all[2].is_self());
+        assert_eq!(all[2].ip(), Ipv4Addr::new(10, 0, 0, 3));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let nodes = Arc::new(Nodes::new());
+        let rejected = Arc::new(RejectCounters::default());
+        let mut listener = Listener::new(
+            Arc::clone(&nodes),
+            Vec::new(),
+            BROADCAST_INTERVAL,
+            Arc::clone(&rejected),
+            None,
+        )
+        .with_rate_limit(Some(RateLimit {
+            per_second: 1.0,
+            burst: 2,
+        }));
+
+        let flooder = SocketAddr::from(([10, 0, 0, 2], 9000));
+        for _ in 0..5 {
+            listener.handle(&[10, 0, 0, 2], &flooder);
+        }
+        assert_eq!(rejected.get(RejectReason::RateLimited), 3);
+        assert!(nodes.contains(&Ipv4Addr::new(10, 0, 0, 2)));
+
+        // a well-behaved peer still gets through
+        listener.handle(&[10, 0, 0, 3], &SocketAddr::from(([10, 0, 0, 3], 9000)));
+        assert!(nodes.contains(&Ipv4Addr::new(10, 0, 0, 3)));
+        assert_eq!(rejected.get(RejectReason::RateLimited), 3);
+
+        // the bucket refills with time
+        let mut limiter = RateLimiter::new(RateLimit {
+            per_second: 1.0,
+            burst: 1,
+        });
+        let src = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let now = Instant::now();
+        assert!(limiter.allow(src, now));
+        assert!(!limiter.allow(src, now));
+        assert!(limiter.allow(src, now + Duration::from_secs(1)));
+
+        // refilled buckets are forgotten at the next report
+        limiter.report(now + Duration::from_secs(60));
+        assert!(limiter.buckets.is_empty());
+    }
+
+    #[test]
+    fn test_deferred_metadata() {
+        let nodes = Arc::new(Nodes::new());
+        let named = Ipv4Addr::new(10, 0, 0, 2);
+        let unnamed = Ipv4Addr::new(10, 0, 0, 3);
+        // as DNS discovery names a node
+        nodes.add(named, Some("uk-lon".into()), Some(1));
+        nodes.add(unnamed, None, None);
+
+        let mut listener = Listener::new(
+            Arc::clone(&nodes),
+            Vec::new(),
+            BROADCAST_INTERVAL,
+            Arc::new(RejectCounters::default()),
+            None,
+        )
+        .with_deferred_metadata(true);
+        for ip in [named, unnamed] {
+            let announcement = Announcement {
+                tag: Some("us-nyc".into()),
+                seq: Some(7),
+                ..Announcement::new(Kind::Announce, vec![ip])
+            }
+            .encode();
+            listener.handle(&announcement, &SocketAddr::from((ip, 9000)));
+        }
+
+        let node = nodes.get(&named).unwrap();
+        assert_eq!(node.tag().map(String::as_str), Some("uk-lon"));
+        assert_eq!(node.seq(), Some(1));
+        let node = nodes.get(&unnamed).unwrap();
+        assert_eq!(node.tag().map(String::as_str), Some("us-nyc"));
+        assert_eq!(node.seq(), Some(7));
+    }
+
+    #[test]
+    fn test_own_secondary_address_ignored() {
+        let nodes = Arc::new(Nodes::new());
+        let primary = Ipv4Addr::new(10, 0, 0, 1);
+        let secondary = Ipv4Addr::new(10, 1, 0, 1);
+        nodes.add_own_ip(primary);
+        let rejected = Arc::new(RejectCounters::default());
+        let mut listener = Listener::new(
+            Arc::clone(&nodes),
+            Vec::new(),
+            BROADCAST_INTERVAL,
+            Arc::clone(&rejected),
+            None,
+        );
+
+        // looped back from an address we never registered, listed first
+        let looped =
+            Announcement::new(Kind::Announce, vec![Ipv4Addr::new(10, 2, 0, 1), primary]).encode();
+        listener.handle(&looped, &SocketAddr::from(([10, 2, 0, 1], 9000)));
+        // from an address another discovery source registered after we started
+        nodes.add_own_ip(secondary);
+        listener.handle(&[10, 1, 0, 1], &SocketAddr::from((secondary, 9000)));
+
+        assert_eq!(rejected.get(RejectReason::OwnAddress), 2);
+        assert!(nodes.is_empty());
+    }
+
+    #[test]
+    fn test_until_boundary() {
+        let interval = Duration::from_secs(5);
+        assert_eq!(
+            until_boundary(Duration::from_millis(12_300), interval),
+            Duration::from_millis(2_700)
+        );
+        assert_eq!(until_boundary(Duration::from_secs(15), interval), interval);
+    }
+
+    #[test]
+    fn test_own_goodbye_ignored() {
+        let nodes = Arc::new(Nodes::new());
+        let own = Ipv4Addr::new(10, 0, 0, 1);
+        let peer = Ipv4Addr::new(10, 0, 0, 2);
+        nodes.add_own_ip(own);
+        nodes.add(peer, None, None);
+        let mut events = nodes.events();
+        let rejected = Arc::new(RejectCounters::default());
+        let mut listener = Listener::new(
+            Arc::clone(&nodes),
+            Vec::new(),
+            BROADCAST_INTERVAL,
+            Arc::clone(&rejected),
+            None,
+        );
+
+        let goodbye = Announcement::new(Kind::Goodbye, vec![own]).encode();
+        listener.handle(&goodbye, &SocketAddr::from((own, 9000)));
+        // looped back from a secondary address we hadn't registered
+        listener.handle(&goodbye, &SocketAddr::from(([10, 0, 0, 3], 9000)));
+
+        assert!(listener.departed.is_empty());
+        assert_eq!(rejected.get(RejectReason::OwnAddress), 2);
+        assert!(events.try_recv().is_err());
+        let all = nodes.all();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].ip(), peer);
+    }
+
+    #[test]
+    fn test_signed_announcements() {
+        let nodes = Arc::new(Nodes::new());
+        let rejected = Arc::new(RejectCounters::default());
+        let max_skew = Duration::from_secs(30);
+        let mut listener = Listener::new(
+            Arc::clone(&nodes),
+            Vec::new(),
+            BROADCAST_INTERVAL,
+            Arc::clone(&rejected),
+            None,
+        )
+        .with_auth(Some(Auth {
+            secret: b"secret".to_vec(),
+            max_skew,
+        }));
+
+        let peer = Ipv4Addr::new(10, 0, 0, 2);
+        let src = SocketAddr::from((peer, 9000));
+        let payload = Announcement::new(Kind::Announce, vec![peer]).encode();
+
+        listener.handle(&payload, &src);
+        listener.handle(&sign(&payload, Some(&b"wrong"[..])), &src);
+        let stale = SystemTime::now() - max_skew * 2;
+        listener.handle(&wire::seal(&payload, b"secret", stale), &src);
+        // replayed by another host
+        let sealed = sign(&payload, Some(&b"secret"[..]));
+        listener.handle(&sealed, &SocketAddr::from(([10, 0, 0, 3], 9000)));
+        assert!(nodes.all().is_empty());
+        assert_eq!(rejected.get(RejectReason::BadSignature), 3);
+        assert_eq!(rejected.get(RejectReason::ClockSkew), 1);
+
+        listener.handle(&sealed, &src);
+        assert!(nodes.contains(&peer));
+    }
+
+    #[test]
+    fn test_announced_tag_and_seq() {
+        let nodes = Arc::new(Nodes::new());
+        let mut listener = Listener::new(
+            Arc::clone(&nodes),
+            Vec::new(),
+            BROADCAST_INTERVAL,
+            Arc::new(RejectCounters::default()),
+            None,
+        );
+
+        let peer = Ipv4Addr::new(10, 0, 0, 2);
+        let announcement = Announcement {
+            tag: Some("uk-lon".into()),
+            seq: Some(4),
+            ..Announcement::new(Kind::Announce, vec![peer])
+        }
+        .encode();
+        listener.handle(&announcement, &SocketAddr::from((peer, 9000)));
+
+        let all = nodes.all();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].tag().map(String::as_str), Some("uk-lon"));
+        assert_eq!(all[0].seq(), Some(4));
+    }
+
+    #[test]
+    fn test_rejected_reasons() {
+        let nodes = Arc::new(Nodes::new());
+        let rejected = Arc::new(RejectCounters::default());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_clone = Arc::clone(&seen);
+        let on_rejected: RejectCallback = Arc::new(move |src, reason| {
+            seen_clone.lock().unwrap().push((src, reason));
+        });
+        let mut listener = Listener::new(
+            Arc::clone(&nodes),
+            Vec::new(),
+            BROADCAST_INTERVAL,
+            Arc::clone(&rejected),
+            Some(on_rejected),
+        );
+
+        let public = SocketAddr::from(([203, 0, 113, 5], 9000));
+        listener.handle(&[203, 0, 113, 5], &public);
+
+        let peer = SocketAddr::from(([10, 0, 0, 2], 9000));
+        let goodbye = Announcement::new(Kind::Goodbye, vec![Ipv4Addr::new(10, 0, 0, 2)]).encode();
+        listener.handle(&goodbye, &peer);
+        listener.handle(&[10, 0, 0, 2], &peer);
+
+        // neither an announcement nor a legacy address
+        let stranger = SocketAddr::from(([10, 0, 0, 3], 9000));
+        listener.handle(b"GET / HTTP/1.1", &stranger);
+        assert!(!nodes.contains(&Ipv4Addr::new(10, 0, 0, 3)));
+
+        assert_eq!(rejected.get(RejectReason::NonPrivateSource), 1);
+        assert_eq!(rejected.get(RejectReason::Departed), 1);
+        assert_eq!(rejected.get(RejectReason::Malformed), 1);
+        assert_eq!(rejected.get(RejectReason::OwnAddress), 0);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                (public, RejectReason::NonPrivateSource),
+                (peer, RejectReason::Departed),
+                (stranger, RejectReason::Malformed)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_prioritize_interfaces() {
+        let candidates = vec![
+            ("eth1".to_string(), Ipv4Addr::new(10, 1, 0, 1)),
+            ("eth0".to_string(), Ipv4Addr::new(10, 0, 0, 2)),
+            ("bond0".to_string(), Ipv4Addr::new(10, 2, 0, 1)),
+            ("eth0".to_string(), Ipv4Addr::new(10, 0, 0, 1)),
+        ];
+
+        assert_eq!(
+            prioritize(candidates.clone(), &[]),
+            vec![
+                Ipv4Addr::new(10, 2, 0, 1),
+                Ipv4Addr::new(10, 0, 0, 1),
+                Ipv4Addr::new(10, 0, 0, 2),
+                Ipv4Addr::new(10, 1, 0, 1),
+            ]
+        );
+        assert_eq!(
+            prioritize(candidates, &["eth1".to_string(), "eth0".to_string()]),
+            vec![
+                Ipv4Addr::new(10, 1, 0, 1),
+                Ipv4Addr::new(10, 0, 0, 1),
+                Ipv4Addr::new(10, 0, 0, 2),
+                Ipv4Addr::new(10, 2, 0, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_allowed_networks() {
+        let any_private = SocketAddr::from(([192, 168, 1, 5], 9000));
+        assert_eq!(
+            extract_private_ip(&any_private, &[]),
+            Some(Ipv4Addr::new(192, 168, 1, 5))
+        );
+        assert!(extract_private_ip(&SocketAddr::from(([172, 20, 0, 2], 9000)), &[]).is_some());
+        assert!(extract_private_ip(&SocketAddr::from(([172, 32, 0, 2], 9000)), &[]).is_none());
+
+        let networks = vec![Ipv4Cidr::from_str("10.1.0.0/16").unwrap()];
+        assert!(extract_private_ip(&any_private, &networks).is_none());
+        assert!(extract_private_ip(&SocketAddr::from(([10, 1, 9, 9], 9000)), &networks).is_some());
+        assert!(extract_private_ip(&SocketAddr::from(([10, 2, 0, 1], 9000)), &networks).is_none());
+
+        assert!(Ipv4Cidr::from_str("10.0.0.0/33").is_err());
+        assert!(Ipv4Cidr::from_str("10.0.0.0").is_err());
+        assert!(Ipv4Cidr::from_str("0.0.0.0/0")
+            .unwrap()
+            .contains(&Ipv4Addr::new(8, 8, 8, 8)));
+        assert_eq!(
+            subnet_broadcast(Ipv4Addr::new(192, 168, 1, 20)),
+            Ipv4Addr::new(192, 168, 1, 255)
+        );
+    }
+
+    #[test]
+    fn test_target_ip() {
+        let own = Ipv4Addr::new(10, 1, 2, 3);
+        assert_eq!(
+            target_ip(VlanTransport::Broadcast, own),
+            Ipv4Addr::new(10, 1, 2, 255)
+        );
+        let group = Ipv4Addr::new(239, 255, 0, 1);
+        assert_eq!(target_ip(VlanTransport::Multicast(group), own), group);
+    }
+
+    #[tokio::test]
+    async fn test_discover_errors() {
+        let config = VlanConfig {
+            preferred_interfaces: vec!["no-such-if0".into()],
+            ..Default::default()
+        };
+        assert!(matches!(
+            discover(0, config).await,
+            Err(DiscoveryError::InvalidInterface(name)) if name == "no-such-if0"
+        ));
+
+        // TEST-NET-1 is never assigned to a real interface
+        let config = VlanConfig {
+            allowed_networks: vec!["192.0.2.0/24".parse().unwrap()],
+            ..Default::default()
+        };
+        assert!(matches!(
+            discover(0, config).await,
+            Err(DiscoveryError::NoPrivateIp)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_own_ip_override() {
+        // TEST-NET-1 is never assigned to a real interface, so only the
+        // override can make this work
+        let own = Ipv4Addr::new(192, 0, 2, 10);
+        let config = VlanConfig {
+            socket: Some(std::net::UdpSocket::bind("127.0.0.1:0").unwrap()),
+            probe_timeout: None,
+            announce_leaving: false,
+            allowed_networks: vec!["192.0.2.0/24".parse().unwrap()],
+            own_ip: Some(own),
+            ..Default::default()
+        };
+        let handle = discover(0, config).await.unwrap();
+        assert!(handle.nodes.is_own_ip(&own));
+        assert_eq!(handle.config().own_ip, Some(own));
+        handle.shutdown.send(()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_probe_failure() {
+        // announcements to TEST-NET-1 from a loopback socket never echo back
+        let config = |on_probe_failure| VlanConfig {
+            socket: Some(std::net::UdpSocket::bind("127.0.0.1:0").unwrap()),
+            probe_timeout: Some(Duration::from_millis(50)),
+            on_probe_failure,
+            announce_leaving: false,
+            allowed_networks: vec!["192.0.2.0/24".parse().unwrap()],
+            own_ip: Some(Ipv4Addr::new(192, 0, 2, 10)),
+            ..Default::default()
+        };
+        assert!(matches!(
+            discover(0, config(ProbeFailurePolicy::Refuse)).await,
+            Err(DiscoveryError::ProbeFailed(_))
+        ));
+
+        let handle = discover(0, config(ProbeFailurePolicy::Warn)).await.unwrap();
+        handle.shutdown.send(()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_port_in_use() {
+        let taken = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let config = VlanConfig {
+            probe_timeout: None,
+            own_ip: Some(Ipv4Addr::LOCALHOST),
+            ..Default::default()
+        };
+        assert!(matches!(
+            discover(port, config).await,
+            Err(DiscoveryError::Bind(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_multicast_requires_group() {
+        let config = VlanConfig {
+            transport: VlanTransport::Multicast(Ipv4Addr::new(10, 0, 0, 1)),
+            ..Default::default()
+        };
+        assert!(discover(0, config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fin_after_shutdown() {
+        let config = VlanConfig {
+            socket: Some(std::net::UdpSocket::bind("127.0.0.1:0").unwrap()),
+            probe_timeout: None,
+            announce_leaving: false,
+            own_ip: Some(Ipv4Addr::LOCALHOST),
+            ..Default::default()
+        };
+        let handle = discover(0, config).await.unwrap();
+        assert!(handle.nodes.is_own_ip(&Ipv4Addr::LOCALHOST));
+
+        handle.shutdown.send(()).unwrap();
+        timeout(Duration::from_secs(1), handle.fin)
+            .await
+            .expect("fin should fire once both tasks stop")
+            .unwrap();
+    }
 }
@@ -0,0 +1,356 @@
+//! Wire format for VLAN announcements.
+//!
+//! ```text
+//! +-------+------+-------+---------------------+------------------+
+//! | "WD"  | kind | count | count * IPv4 octets | metadata (opt.)  |
+//! +-------+------+-------+---------------------+------------------+
+//!  2 bytes 1 byte 1 byte   4 bytes each
+//! ```
+//!
+//! `kind` is 0 for a periodic announcement and 1 for a goodbye sent on
+//! shutdown. A bare 4-byte datagram is the legacy payload: just the sender's
+//! address, read as an announcement.
+//!
+//! The optional metadata carries the sender's tag and seq:
+//!
+//! ```text
+//! +---------+-----------+---------+--------------+
+//! | tag_len | tag bytes | has_seq | seq (BE u32) |
+//! +---------+-----------+---------+--------------+
+//!  1 byte    0-255       1 byte    4 bytes if has_seq is 1
+//! ```
+//!
+//! A `tag_len` of 0 means no tag. Datagrams without metadata decode with
+//! neither, so older senders are still understood.
+//!
+//! With a shared secret, [`seal`] appends the send time and an HMAC-SHA256
+//! over everything before it:
+//!
+//! ```text
+//! +--------------+------------------------+--------------------+
+//! | announcement | timestamp (BE u64, ms) | HMAC-SHA256        |
+//! +--------------+------------------------+--------------------+
+//!                  8 bytes                  32 bytes
+//! ```
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::net::Ipv4Addr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const MAGIC: [u8; 2] = *b"WD";
+const KIND_ANNOUNCE: u8 = 0;
+const KIND_GOODBYE: u8 = 1;
+const HEADER_LEN: usize = 4;
+
+/// Upper bound on addresses carried by one announcement.
+pub const MAX_ADDRS: usize = 32;
+/// Longest tag, in bytes, that fits in an announcement.
+pub const MAX_TAG_LEN: usize = u8::MAX as usize;
+
+const TIMESTAMP_LEN: usize = 8;
+const MAC_LEN: usize = 32;
+/// Bytes [`seal`] adds to a payload.
+pub const AUTH_LEN: usize = TIMESTAMP_LEN + MAC_LEN;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Announce,
+    /// The sender is shutting down; forget it now rather than on timeout.
+    Goodbye,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announcement {
+    pub kind: Kind,
+    /// The sender's own addresses, primary first.
+    pub addrs: Vec<Ipv4Addr>,
+    pub tag: Option<String>,
+    pub seq: Option<u32>,
+}
+
+impl Announcement {
+    pub fn new(kind: Kind, addrs: Vec<Ipv4Addr>) -> Self {
+        Announcement {
+            kind,
+            addrs,
+            tag: None,
+            seq: None,
+        }
+    }
+
+    /// Encodes the announcement. Addresses past [`MAX_ADDRS`] are dropped, and
+    /// a tag longer than [`MAX_TAG_LEN`] is left out entirely rather than cut
+    /// short.
+    pub fn encode(&self) -> Vec<u8> {
+        let addrs = &self.addrs[..self.addrs.len().min(MAX_ADDRS)];
+        let tag = self
+            .tag
+            .as_deref()
+            .filter(|tag| tag.len() <= MAX_TAG_LEN)
+            .unwrap_or("");
+
+        let mut buf = Vec::with_capacity(HEADER_LEN + addrs.len() * 4 + 2 + tag.len() + 4);
+        buf.extend_from_slice(&MAGIC);
+        buf.push(match self.kind {
+            Kind::Announce => KIND_ANNOUNCE,
+            Kind::Goodbye => KIND_GOODBYE,
+        });
+        buf.push(addrs.len() as u8);
+        for addr in addrs {
+            buf.extend_from_slice(&addr.octets());
+        }
+
+        if !tag.is_empty() || self.seq.is_some() {
+            buf.push(tag.len() as u8);
+            buf.extend_from_slice(tag.as_bytes());
+            match self.seq {
+                Some(seq) => {
+                    buf.push(1);
+                    buf.extend_from_slice(&seq.to_be_bytes());
+                }
+                None => buf.push(0),
+            }
+        }
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() == 4 {
+            let ip = Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]);
+            return Some(Announcement::new(Kind::Announce, vec![ip]));
+        }
+
+        if buf.len() < HEADER_LEN || buf[0..2] != MAGIC {
+            return None;
+        }
+
+        let kind = match buf[2] {
+            KIND_ANNOUNCE => Kind::Announce,
+            KIND_GOODBYE => Kind::Goodbye,
+            _ => return None,
+        };
+
+        let count = buf[3] as usize;
+        let addrs_end = HEADER_LEN + count * 4;
+        if count == 0 || count > MAX_ADDRS || buf.len() < addrs_end {
+            return None;
+        }
+
+        let addrs = buf[HEADER_LEN..addrs_end]
+            .chunks_exact(4)
+            .map(|o| Ipv4Addr::new(o[0], o[1], o[2], o[3]))
+            .collect();
+
+        let mut announcement = Announcement::new(kind, addrs);
+        let metadata = &buf[addrs_end..];
+        if !metadata.is_empty() {
+            let (tag, seq) = decode_metadata(metadata)?;
+            announcement.tag = tag;
+            announcement.seq = seq;
+        }
+        Some(announcement)
+    }
+}
+
+fn decode_metadata(buf: &[u8]) -> Option<(Option<String>, Option<u32>)> {
+    let (&tag_len, rest) = buf.split_first()?;
+    let tag_len = tag_len as usize;
+    if rest.len() < tag_len {
+        return None;
+    }
+    let (tag, rest) = rest.split_at(tag_len);
+    let tag = match tag_len {
+        0 => None,
+        _ => Some(std::str::from_utf8(tag).ok()?.to_string()),
+    };
+
+    let seq = match rest {
+        [0] => None,
+        [1, a, b, c, d] => Some(u32::from_be_bytes([*a, *b, *c, *d])),
+        _ => return None,
+    };
+    Some((tag, seq))
+}
+
+/// Why [`open`] refused a datagram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// Too short to be sealed, or the HMAC doesn't match.
+    BadSignature,
+    /// Correctly signed, but sent too long before or after `now`.
+    Skew,
+}
+
+/// Appends the send time and an HMAC keyed with `secret` to `payload`.
+pub fn seal(payload: &[u8], secret: &[u8], now: SystemTime) -> Vec<u8> {
+    let millis = now
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+
+    let mut buf = Vec::with_capacity(payload.len() + AUTH_LEN);
+    buf.extend_from_slice(payload);
+    buf.extend_from_slice(&millis.to_be_bytes());
+    let tag = mac(secret, &buf).finalize().into_bytes();
+    buf.extend_from_slice(&tag);
+    buf
+}
+
+/// Checks a datagram produced by [`seal`] and returns the payload inside it.
+/// The timestamp must be within `max_skew` of `now` in either direction; a
+/// copy replayed inside that window still verifies.
+pub fn open<'a>(
+    buf: &'a [u8],
+    secret: &[u8],
+    now: SystemTime,
+    max_skew: Duration,
+) -> Result<&'a [u8], AuthError> {
+    if buf.len() < AUTH_LEN {
+        return Err(AuthError::BadSignature);
+    }
+    let (signed, tag) = buf.split_at(buf.len() - MAC_LEN);
+    mac(secret, signed)
+        .verify_slice(tag)
+        .map_err(|_| AuthError::BadSignature)?;
+
+    let (payload, timestamp) = signed.split_at(signed.len() - TIMESTAMP_LEN);
+    let mut millis = [0; TIMESTAMP_LEN];
+    millis.copy_from_slice(timestamp);
+    let sent = UNIX_EPOCH + Duration::from_millis(u64::from_be_bytes(millis));
+    let skew = match now.duration_since(sent) {
+        Ok(behind) => behind,
+        Err(e) => e.duration(),
+    };
+    if skew > max_skew {
+        return Err(AuthError::Skew);
+    }
+    Ok(payload)
+}
+
+fn mac(secret: &[u8], data: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_announcement_round_trip() {
+        let announcement = Announcement::new(
+            Kind::Announce,
+            vec![Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1)],
+        );
+        let buf = announcement.encode();
+        assert_eq!(buf.len(), 12);
+        assert_eq!(Announcement::decode(&buf), Some(announcement));
+
+        let goodbye = Announcement::new(Kind::Goodbye, vec![Ipv4Addr::new(10, 0, 0, 1)]);
+        assert_eq!(Announcement::decode(&goodbye.encode()), Some(goodbye));
+    }
+
+    #[test]
+    fn test_announcement_metadata_round_trip() {
+        let mut announcement = Announcement::new(Kind::Announce, vec![Ipv4Addr::new(10, 0, 0, 1)]);
+        announcement.tag = Some("uk-lon".into());
+        announcement.seq = Some(70_000);
+        assert_eq!(
+            Announcement::decode(&announcement.encode()),
+            Some(announcement.clone())
+        );
+
+        announcement.tag = None;
+        assert_eq!(
+            Announcement::decode(&announcement.encode()),
+            Some(announcement.clone())
+        );
+
+        announcement.tag = Some("uk-lon".into());
+        announcement.seq = None;
+        assert_eq!(
+            Announcement::decode(&announcement.encode()),
+            Some(announcement.clone())
+        );
+
+        // too long to send, so left out rather than truncated
+        announcement.tag = Some("x".repeat(MAX_TAG_LEN + 1));
+        let decoded = Announcement::decode(&announcement.encode()).unwrap();
+        assert_eq!(decoded.tag, None);
+    }
+
+    #[test]
+    fn test_announcement_legacy_payload() {
+        let decoded = Announcement::decode(&[10, 0, 0, 9]).unwrap();
+        assert_eq!(decoded.kind, Kind::Announce);
+        assert_eq!(decoded.addrs, vec![Ipv4Addr::new(10, 0, 0, 9)]);
+    }
+
+    #[test]
+    fn test_announcement_rejects_malformed() {
+        let buf = Announcement::new(
+            Kind::Announce,
+            vec![Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1)],
+        )
+        .encode();
+        assert_eq!(Announcement::decode(&buf[..buf.len() - 1]), None);
+        assert_eq!(Announcement::decode(b"XX\x00\x01\x0a\x00\x00\x01"), None);
+        assert_eq!(Announcement::decode(b"WD\x00\x02\x0a\x00\x00\x01"), None);
+        assert_eq!(Announcement::decode(b"WD\x07\x01\x0a\x00\x00\x01"), None);
+
+        let mut announcement = Announcement::new(Kind::Announce, vec![Ipv4Addr::new(10, 0, 0, 1)]);
+        announcement.tag = Some("uk-lon".into());
+        announcement.seq = Some(3);
+        let buf = announcement.encode();
+        // truncated anywhere in the metadata
+        for len in 9..buf.len() {
+            assert_eq!(Announcement::decode(&buf[..len]), None, "len {}", len);
+        }
+        // trailing garbage
+        let mut oversized = buf.clone();
+        oversized.push(0);
+        assert_eq!(Announcement::decode(&oversized), None);
+        // tag that isn't UTF-8
+        assert_eq!(
+            Announcement::decode(b"WD\x00\x01\x0a\x00\x00\x01\x02\xff\xfe\x00"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let payload = Announcement::new(Kind::Announce, vec![Ipv4Addr::new(10, 0, 0, 1)]).encode();
+        let now = SystemTime::now();
+        let skew = Duration::from_secs(30);
+        let sealed = seal(&payload, b"secret", now);
+        assert_eq!(sealed.len(), payload.len() + AUTH_LEN);
+        assert_eq!(open(&sealed, b"secret", now, skew), Ok(&payload[..]));
+
+        assert_eq!(
+            open(&sealed, b"other", now, skew),
+            Err(AuthError::BadSignature)
+        );
+        let mut tampered = sealed.clone();
+        tampered[4] ^= 1;
+        assert_eq!(
+            open(&tampered, b"secret", now, skew),
+            Err(AuthError::BadSignature)
+        );
+        assert_eq!(
+            open(&payload, b"secret", now, skew),
+            Err(AuthError::BadSignature)
+        );
+
+        let later = now + Duration::from_secs(31);
+        assert_eq!(open(&sealed, b"secret", later, skew), Err(AuthError::Skew));
+        let earlier = now - Duration::from_secs(31);
+        assert_eq!(
+            open(&sealed, b"secret", earlier, skew),
+            Err(AuthError::Skew)
+        );
+    }
+}